@@ -3,11 +3,21 @@ fn main() -> Result<(),Box<dyn std::error::Error>> {
         // It is included in the out/user.rs but the compiler says it can not find them.
         .type_attribute(".recommendation_svc.User", "#[derive(serde::Serialize, serde::Deserialize)]")
         .type_attribute(".recommendation_svc.Location", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .type_attribute(".recommendation_svc.Preferences", "#[derive(serde::Serialize, serde::Deserialize)]")
+        // Documents indexed before preferences existed have none
+        .field_attribute(".recommendation_svc.User.preferences", "#[serde(default)]")
         .field_attribute(".recommendation_svc.Location.longitude", "#[serde(rename = \"lon\")]")
         .field_attribute(".recommendation_svc.Location.latitude", "#[serde(rename = \"lat\")]")
         .compile(
             &["proto/recommendation/recommendation.proto"],
             &["proto/recommendation"]
         )?;
+    // Only a client, to fetch the keys access tokens are signed with and check they weren't revoked
+    tonic_build::configure()
+        .build_server(false)
+        .compile(
+            &["../user_service/proto/user.proto"],
+            &["../user_service/proto"]
+        )?;
     Ok(())
 }
\ No newline at end of file
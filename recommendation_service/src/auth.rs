@@ -0,0 +1,451 @@
+use super::quota::env_number;
+use super::user::user_service_client::UserServiceClient;
+use super::user::{GetJwksRequest, Jwk, TokenStatus, ValidateTokenRequest};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::{Request, Status};
+
+// Set by the interceptor, any value sent by the client is replaced
+pub const UID_METADATA: &str = "x-authenticated-uid";
+
+const DEFAULT_ISSUER: &str = "user-service";
+const DEFAULT_AUDIENCE: &str = "date-app";
+const DEFAULT_LEEWAY_SEC: u64 = 30;
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const JWKS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// How long the user service's answer about a token is trusted
+const REVOCATION_CACHE_TTL: Duration = Duration::from_secs(5);
+// Expired answers are dropped once the cache holds this many
+const REVOCATION_PRUNE_THRESHOLD: usize = 100_000;
+
+// Only what's needed here, see Claims in user_service/src/token.rs
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String, // uid
+}
+
+/*
+Verifies the access tokens the user service issues against the keys it
+publishes through GetJwks. This is done offline, whether the token was
+revoked since is up to RevocationCheck.
+*/
+pub struct Authenticator {
+    keys: RwLock<HashMap<String, DecodingKey<'static>>>,
+    validation: Validation,
+}
+
+impl Authenticator {
+    pub fn new(issuer: String, audience: String, leeway: u64) -> Self {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.leeway = leeway;
+        validation.validate_nbf = true;
+        validation.iss = Some(issuer);
+        validation.set_audience(&[audience]);
+        Self {
+            keys: RwLock::new(HashMap::new()),
+            validation,
+        }
+    }
+
+    // JWT_ISSUER, JWT_AUDIENCE and JWT_LEEWAY_SEC must match the user service
+    pub fn from_env() -> Self {
+        Self::new(
+            env::var("JWT_ISSUER").unwrap_or_else(|_| DEFAULT_ISSUER.to_string()),
+            env::var("JWT_AUDIENCE").unwrap_or_else(|_| DEFAULT_AUDIENCE.to_string()),
+            env_number("JWT_LEEWAY_SEC").unwrap_or(DEFAULT_LEEWAY_SEC),
+        )
+    }
+
+    pub fn set_keys(&self, jwks: Vec<Jwk>) {
+        let keys = jwks
+            .into_iter()
+            .filter(|jwk| jwk.kty == "RSA")
+            .map(|jwk| {
+                let key = DecodingKey::from_rsa_components(&jwk.n, &jwk.e).into_static();
+                (jwk.kid, key)
+            })
+            .collect();
+        *self.keys.write().unwrap() = keys;
+    }
+
+    // The uid the bearer token was issued to
+    pub fn verify(&self, token: &str) -> Result<String, Status> {
+        let kid = decode_header(token)
+            .ok()
+            .and_then(|header| header.kid)
+            .ok_or_else(|| Status::unauthenticated("invalid token"))?;
+        let keys = self.keys.read().unwrap();
+        let key = keys
+            .get(&kid)
+            .ok_or_else(|| Status::unauthenticated("invalid token"))?;
+        match decode::<Claims>(token, key, &self.validation) {
+            Ok(data) => Ok(data.claims.sub),
+            Err(err) => {
+                info!("Token rejected: {}", err);
+                Err(Status::unauthenticated("invalid token"))
+            }
+        }
+    }
+
+    // Interceptor for every RPC of the service
+    pub fn intercept(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let uid = self.verify(bearer_token(&request)?)?;
+        let uid =
+            MetadataValue::from_str(&uid).map_err(|_| Status::unauthenticated("invalid token"))?;
+        request.metadata_mut().insert(UID_METADATA, uid);
+        Ok(request)
+    }
+}
+
+fn bearer_token<T>(request: &Request<T>) -> Result<&str, Status> {
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .ok_or_else(|| Status::unauthenticated("missing bearer token"))
+}
+
+/*
+Asks the user service through ValidateToken whether a token that passed
+the interceptor was revoked since, signing out or a password change
+takes effect here within REVOCATION_CACHE_TTL. Requests fail with
+UNAVAILABLE while the user service can't answer.
+*/
+pub struct RevocationCheck {
+    url: String,
+    client: Mutex<Option<UserServiceClient<Channel>>>,
+    // Keyed by the whole token, whether it was still valid and when that was asked
+    answers: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl RevocationCheck {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: Mutex::new(None),
+            answers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn check<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let token = bearer_token(request)?.to_string();
+        let valid = match self.cached(&token, Instant::now()) {
+            Some(valid) => valid,
+            None => {
+                let valid = self.validate(&token).await?;
+                self.remember(token, valid, Instant::now());
+                valid
+            }
+        };
+        if !valid {
+            return Err(Status::unauthenticated("invalid token"));
+        }
+        Ok(())
+    }
+
+    async fn validate(&self, token: &str) -> Result<bool, Status> {
+        let unavailable = |err: &dyn std::fmt::Display| {
+            error!("Err validating a token with {}: {}", self.url, err);
+            Status::unavailable("can't check the token, try again")
+        };
+        let cached = self.client.lock().unwrap().clone();
+        let mut client = match cached {
+            Some(client) => client,
+            None => {
+                let client = UserServiceClient::connect(self.url.clone())
+                    .await
+                    .map_err(|err| unavailable(&err))?;
+                *self.client.lock().unwrap() = Some(client.clone());
+                client
+            }
+        };
+        let response = client
+            .validate_token(Request::new(ValidateTokenRequest {
+                token: token.to_string(),
+            }))
+            .await
+            .map_err(|err| unavailable(&err))?;
+        let status = response.into_inner().status;
+        if status != TokenStatus::Valid as i32 {
+            info!("Token rejected by the user service: {}", status);
+        }
+        Ok(status == TokenStatus::Valid as i32)
+    }
+
+    fn cached(&self, token: &str, now: Instant) -> Option<bool> {
+        match self.answers.lock().unwrap().get(token) {
+            Some(&(valid, asked_at)) if now.duration_since(asked_at) < REVOCATION_CACHE_TTL => {
+                Some(valid)
+            }
+            _ => None,
+        }
+    }
+
+    fn remember(&self, token: String, valid: bool, now: Instant) {
+        let mut answers = self.answers.lock().unwrap();
+        if answers.len() >= REVOCATION_PRUNE_THRESHOLD {
+            answers.retain(|_, (_, asked_at)| now.duration_since(*asked_at) < REVOCATION_CACHE_TTL);
+        }
+        answers.insert(token, (valid, now));
+    }
+}
+
+// Only present once the interceptor let the request through
+pub fn authenticated_uid<T>(request: &Request<T>) -> Result<String, Status> {
+    request
+        .metadata()
+        .get(UID_METADATA)
+        .and_then(|uid| uid.to_str().ok())
+        .map(|uid| uid.to_string())
+        .ok_or_else(|| Status::unauthenticated("missing bearer token"))
+}
+
+pub async fn fetch_jwks(url: &str) -> Result<Vec<Jwk>, Box<dyn std::error::Error>> {
+    let mut client = UserServiceClient::connect(url.to_string()).await?;
+    let response = client.get_jwks(Request::new(GetJwksRequest {})).await?;
+    Ok(response.into_inner().keys)
+}
+
+// Retries until the user service answers, requests can't be verified without keys
+pub async fn load_keys(authenticator: &Authenticator, url: &str) {
+    loop {
+        match fetch_jwks(url).await {
+            Ok(jwks) => {
+                info!("Loaded {} signing keys from {}", jwks.len(), url);
+                authenticator.set_keys(jwks);
+                return;
+            }
+            Err(err) => {
+                warn!("Err fetching JWKS from {}, retrying: {}", url, err);
+                delay_for(JWKS_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+// Picks up rotated keys, the current ones are kept when a fetch fails
+pub async fn refresh_keys(authenticator: Arc<Authenticator>, url: String) {
+    loop {
+        delay_for(JWKS_REFRESH_INTERVAL).await;
+        match fetch_jwks(&url).await {
+            Ok(jwks) => authenticator.set_keys(jwks),
+            Err(err) => error!("Err refreshing JWKS from {}: {}", url, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use base64::{encode_config, URL_SAFE_NO_PAD};
+    use futures::executor::block_on;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use openssl::rsa::Rsa;
+    use serde_json::Value;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tonic::Code;
+
+    const KID: &str = "test-key";
+
+    // An authenticator trusting a freshly generated key, and that key to sign with
+    fn authenticator() -> (Authenticator, EncodingKey) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let encoding_key = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+        let authenticator = Authenticator::new(
+            DEFAULT_ISSUER.to_string(),
+            DEFAULT_AUDIENCE.to_string(),
+            DEFAULT_LEEWAY_SEC,
+        );
+        authenticator.set_keys(vec![Jwk {
+            kty: "RSA".to_string(),
+            kid: KID.to_string(),
+            alg: "RS256".to_string(),
+            key_use: "sig".to_string(),
+            n: encode_config(&rsa.n().to_vec(), URL_SAFE_NO_PAD),
+            e: encode_config(&rsa.e().to_vec(), URL_SAFE_NO_PAD),
+        }]);
+        (authenticator, encoding_key)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    // Claims like the user service's access tokens, valid for the next 15 minutes
+    fn claims() -> Value {
+        json!({
+            "iss": DEFAULT_ISSUER,
+            "sub": "uid-1",
+            "aud": DEFAULT_AUDIENCE,
+            "exp": now() + 15 * 60,
+            "iat": now(),
+            "nbf": now(),
+            "jti": "jti-1",
+        })
+    }
+
+    fn sign(claims: &Value, kid: Option<&str>, key: &EncodingKey) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = kid.map(|kid| kid.to_string());
+        encode(&header, claims, key).unwrap()
+    }
+
+    fn rejected(result: Result<String, Status>) -> bool {
+        matches!(result, Err(status) if status.code() == Code::Unauthenticated)
+    }
+
+    fn bearer(authorization: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", authorization.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn accepts_a_valid_token() {
+        let (authenticator, key) = authenticator();
+        let token = sign(&claims(), Some(KID), &key);
+        assert_eq!(authenticator.verify(&token).unwrap(), "uid-1");
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let (authenticator, key) = authenticator();
+        let mut claims = claims();
+        claims["iat"] = json!(now() - 2 * 60 * 60);
+        claims["nbf"] = claims["iat"].clone();
+        claims["exp"] = json!(now() - 60 * 60);
+        assert!(rejected(authenticator.verify(&sign(
+            &claims,
+            Some(KID),
+            &key
+        ))));
+    }
+
+    #[test]
+    fn rejects_another_issuer() {
+        let (authenticator, key) = authenticator();
+        let mut claims = claims();
+        claims["iss"] = json!("someone-else");
+        assert!(rejected(authenticator.verify(&sign(
+            &claims,
+            Some(KID),
+            &key
+        ))));
+    }
+
+    #[test]
+    fn rejects_challenge_tokens() {
+        let (authenticator, key) = authenticator();
+        let mut claims = claims();
+        claims["aud"] = json!("date-app/second-factor");
+        claims["username"] = json!("user");
+        claims["device"] = json!("phone");
+        assert!(rejected(authenticator.verify(&sign(
+            &claims,
+            Some(KID),
+            &key
+        ))));
+    }
+
+    #[test]
+    fn rejects_unknown_and_missing_kids() {
+        let (authenticator, key) = authenticator();
+        assert!(rejected(authenticator.verify(&sign(
+            &claims(),
+            Some("other"),
+            &key
+        ))));
+        assert!(rejected(authenticator.verify(&sign(&claims(), None, &key))));
+    }
+
+    #[test]
+    fn rejects_a_key_that_isnt_published() {
+        let (_, other_key) = authenticator();
+        let (authenticator, _) = authenticator();
+        assert!(rejected(authenticator.verify(&sign(
+            &claims(),
+            Some(KID),
+            &other_key
+        ))));
+    }
+
+    #[test]
+    fn requires_a_bearer_token() {
+        let (authenticator, key) = authenticator();
+        let token = sign(&claims(), Some(KID), &key);
+        assert!(authenticator.intercept(Request::new(())).is_err());
+        assert!(authenticator.intercept(bearer(&token)).is_err());
+        assert!(authenticator
+            .intercept(bearer(&format!("Basic {}", token)))
+            .is_err());
+        assert!(authenticator
+            .intercept(bearer(&format!("Bearer {}", token)))
+            .is_ok());
+    }
+
+    #[test]
+    fn replaces_the_uid_sent_by_the_client() {
+        let (authenticator, key) = authenticator();
+        let token = sign(&claims(), Some(KID), &key);
+        let mut request = bearer(&format!("Bearer {}", token));
+        request
+            .metadata_mut()
+            .insert(UID_METADATA, "uid-2".parse().unwrap());
+        let request = authenticator.intercept(request).unwrap();
+        assert_eq!(authenticated_uid(&request).unwrap(), "uid-1");
+        assert_eq!(request.metadata().get_all(UID_METADATA).iter().count(), 1);
+    }
+
+    // Nothing listens there, only cached answers can be given
+    fn revocation_check() -> RevocationCheck {
+        RevocationCheck::new("http://127.0.0.1:1".to_string())
+    }
+
+    #[test]
+    fn rejects_a_revoked_token() {
+        let (_, key) = authenticator();
+        let token = sign(&claims(), Some(KID), &key);
+        let check = revocation_check();
+        check.remember(token.clone(), false, Instant::now());
+        let status = block_on(check.check(&bearer(&format!("Bearer {}", token)))).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let mut claims = claims();
+        claims["jti"] = json!("jti-2");
+        let other = sign(&claims, Some(KID), &key);
+        check.remember(other.clone(), true, Instant::now());
+        assert!(block_on(check.check(&bearer(&format!("Bearer {}", other)))).is_ok());
+    }
+
+    #[test]
+    fn asks_again_once_the_answer_is_stale() {
+        let check = revocation_check();
+        let asked_at = Instant::now();
+        check.remember("token".to_string(), true, asked_at);
+        assert_eq!(check.cached("token", asked_at), Some(true));
+        assert_eq!(check.cached("token", asked_at + REVOCATION_CACHE_TTL), None);
+        assert_eq!(check.cached("other", asked_at), None);
+    }
+
+    #[tokio::test]
+    async fn fails_closed_when_the_user_service_is_down() {
+        let status = revocation_check()
+            .check(&bearer("Bearer token"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+    }
+}
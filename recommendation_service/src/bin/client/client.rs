@@ -2,6 +2,8 @@ extern crate recommendation_service;
 
 use env_logger::init;
 use log::info;
+use std::env;
+use tonic::metadata::MetadataValue;
 use tonic::Request;
 
 use recommendation_service::recommendation::{
@@ -16,14 +18,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await
         .unwrap();
     let mut client = RecommendationServiceClient::new(channel);
-    let request = Request::new(GetQueueRequest {
-        uid: "541fe12b-e1ab-45e6-a558-d2ddaac310b6".to_string(),
+    let mut request = Request::new(GetQueueRequest {
+        uid: "".to_string(), // the token's uid
         longitude: -132.8896,
         latitude: 67.7974,
         radius: 50,
         age_range: vec![21, 30],
-        gender: Gender::Female as i32,
+        genders: vec![Gender::Female as i32],
+        ..GetQueueRequest::default()
     });
+    // An access token from the user service's Auth RPC
+    let jwt = env::var("JWT").expect("JWT must be set to an access token");
+    request.metadata_mut().insert(
+        "authorization",
+        MetadataValue::from_str(&format!("Bearer {}", jwt)).unwrap(),
+    );
     let mut result = client.get_queue(request).await.unwrap().into_inner();
 
     while let Some(user) = result.message().await.unwrap() {
@@ -2,9 +2,13 @@ extern crate recommendation_service;
 
 use recommendation_service::recommendation::User;
 
+use recommendation_service::elastic::blocks::build_block_index;
+use recommendation_service::elastic::matches::build_match_index;
 use recommendation_service::elastic::ops::{
     build_geoshard_mapping_index, build_geosharded_indices,
 };
+use recommendation_service::elastic::reports::build_report_index;
+use recommendation_service::elastic::swipes::build_swipe_index;
 
 use futures::join;
 use recommendation_service::elastic::ops::ElasticOperator;
@@ -47,9 +51,28 @@ async fn main() {
     info!("Building Geoshard Indices");
     let create_indices_ftr = build_geosharded_indices(&client, &shards);
 
-    join!(create_indices_ftr, create_mapping_ftr);
+    info!("Building Swipe Index");
+    let create_swipes_ftr = build_swipe_index(&client);
+
+    info!("Building Match Index");
+    let create_matches_ftr = build_match_index(&client);
+
+    info!("Building Block and Report Indices");
+    let create_blocks_ftr = build_block_index(&client);
+    let create_reports_ftr = build_report_index(&client);
+
+    join!(
+        create_indices_ftr,
+        create_mapping_ftr,
+        create_swipes_ftr,
+        create_matches_ftr,
+        create_blocks_ftr,
+        create_reports_ftr
+    );
 
     let elastic_operator = ElasticOperator::new(client);
+    // Nothing to renumber in new indices, recorded so the server doesn't wait for it
+    elastic_operator.migrate_genders().await.unwrap();
     let service = MainRecommendactionService::new(elastic_operator).await;
     // TODO Implement bulk load, can't because one node
     service.new_users(users).await;
@@ -0,0 +1,19 @@
+extern crate recommendation_service;
+
+use elasticsearch::{http::transport::Transport, Elasticsearch};
+use env_logger::init;
+use log::info;
+use recommendation_service::elastic::ops::ElasticOperator;
+
+// Brings indexed documents up to date, run before deploying a release that needs it
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init();
+    info!("ES @ http://localhost:9200");
+    let transport = Transport::single_node("http://localhost:9200")?;
+    let elastic_operator = ElasticOperator::new(Elasticsearch::new(transport));
+
+    elastic_operator.map_preferences().await?;
+    elastic_operator.migrate_genders().await?;
+    Ok(())
+}
@@ -3,9 +3,15 @@ extern crate recommendation_service;
 use elasticsearch::{http::transport::Transport, Elasticsearch};
 use env_logger::init;
 use log::info;
+use recommendation_service::auth::{load_keys, refresh_keys, Authenticator, RevocationCheck};
+use recommendation_service::elastic::migrations::GENDER_MIGRATION;
 use recommendation_service::elastic::ops::ElasticOperator;
+use recommendation_service::events::{consume, file_events, kafka_events};
+use recommendation_service::quota::Quotas;
 use recommendation_service::recommendation::recommendation_service_server::RecommendationServiceServer;
-use recommendation_service::service::MainRecommendactionService;
+use recommendation_service::service::{MainRecommendactionService, SwipeLimits};
+use std::env;
+use std::sync::Arc;
 use tonic::transport::Server;
 
 #[tokio::main]
@@ -13,12 +19,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     init();
     info!("Starting recommendation-Service");
 
-    let elastic_operator = ElasticOperator::new(Elasticsearch::new(
-        Transport::single_node("http://localhost:9200").unwrap(),
-    ));
+    let client = Elasticsearch::new(Transport::single_node("http://localhost:9200").unwrap());
+    let elastic_operator = ElasticOperator::new(client);
 
-    let rec_service =
-        RecommendationServiceServer::new(MainRecommendactionService::new(elastic_operator).await);
+    // Keeps the shards in sync with the user service
+    let deliveries = match env::var("EVENTS_KAFKA_BROKERS") {
+        Ok(brokers) => {
+            let topic = env::var("EVENTS_TOPIC").unwrap_or_else(|_| "user-events".to_string());
+            let group =
+                env::var("EVENTS_GROUP").unwrap_or_else(|_| "recommendation-service".to_string());
+            info!("Consuming user events from {} on {}", topic, brokers);
+            let brokers = brokers.split(',').map(|host| host.trim().to_string());
+            Some(kafka_events(brokers.collect(), topic, group)?)
+        }
+        Err(_) => env::var("EVENTS_FILE").ok().map(|path| {
+            info!("Reading user events from {}", path);
+            file_events(path.into())
+        }),
+    };
+
+    // Every RPC needs an access token from the user service
+    let jwks_url =
+        env::var("USER_SERVICE_URL").unwrap_or_else(|_| "http://user-service:8080".to_string());
+    let authenticator = Arc::new(Authenticator::from_env());
+    load_keys(&authenticator, &jwks_url).await;
+    tokio::spawn(refresh_keys(authenticator.clone(), jwks_url.clone()));
+
+    // Writes use the current gender numbers, documents with the old ones must be renumbered first
+    elastic_operator.wait_for_migration(GENDER_MIGRATION).await;
+    let service = MainRecommendactionService::new(elastic_operator)
+        .await
+        .with_swipe_limits(SwipeLimits::from_env())
+        .with_quotas(Quotas::from_env())
+        .with_revocation_check(RevocationCheck::new(jwks_url));
+    if let Some(deliveries) = deliveries {
+        tokio::spawn(consume(service.clone(), deliveries));
+    }
+
+    let rec_service = RecommendationServiceServer::with_interceptor(service, move |request| {
+        authenticator.intercept(request)
+    });
 
     let addr = "0.0.0.0:3030".parse().unwrap();
     info!("Server listening on {}", addr);
@@ -0,0 +1,170 @@
+use log::info;
+
+use super::indices::BlockIndex;
+use super::ops::ElasticOperator;
+use elasticsearch::indices::IndicesCreateParts;
+use elasticsearch::params::Conflicts;
+use elasticsearch::{
+    CountParts, DeleteByQueryParts, Elasticsearch, Error, IndexParts, SearchParts,
+};
+use serde_json::value::Value;
+
+// Blocks read per search request when listing a user's blocks
+const BLOCK_PAGE_SIZE: usize = 1000;
+
+// `blocker` doesn't want to see `blocked` again nor be seen by them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockRecord {
+    pub id: String,
+    pub blocker: String,
+    pub blocked: String,
+    pub blocked_at: u64, // epoch millis
+}
+
+impl BlockRecord {
+    pub fn new(blocker: &str, blocked: &str, blocked_at: u64) -> Self {
+        Self {
+            id: block_id(blocker, blocked),
+            blocker: blocker.to_string(),
+            blocked: blocked.to_string(),
+            blocked_at,
+        }
+    }
+
+    // The user on the other side of the block from `uid`
+    pub fn other(&self, uid: &str) -> &str {
+        if self.blocker == uid {
+            &self.blocked
+        } else {
+            &self.blocker
+        }
+    }
+}
+
+fn block_id(blocker: &str, blocked: &str) -> String {
+    format!("{}_{}", blocker, blocked)
+}
+
+pub async fn build_block_index(client: &Elasticsearch) {
+    info!("Building Block Index: {}", BlockIndex::name());
+    let response = client
+        .indices()
+        .create(IndicesCreateParts::Index(BlockIndex::name().as_str()))
+        .body(BlockIndex::body())
+        .send()
+        .await
+        .unwrap();
+    info!(
+        "Sucess for block index {}: {}",
+        BlockIndex::name(),
+        response.status_code().is_success()
+    );
+}
+
+impl ElasticOperator {
+    // Blocking again keeps a single block
+    pub async fn block(&self, record: &BlockRecord) -> Result<(), Error> {
+        info!("User {} blocked {}", record.blocker, record.blocked);
+        self.client
+            .index(IndexParts::IndexId(BlockIndex::name().as_str(), &record.id))
+            .body(record)
+            .send()
+            .await?
+            .error_for_status_code()?;
+        Ok(())
+    }
+
+    // Whether either of the two blocked the other
+    pub async fn is_blocked(&self, a: &str, b: &str) -> Result<bool, Error> {
+        let resp = self
+            .client
+            .count(CountParts::Index(&[BlockIndex::name().as_str()]))
+            .body(json!({
+                "query": { "ids": { "values": [block_id(a, b), block_id(b, a)] } }
+            }))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        let json: Value = resp.json().await?;
+        Ok(json["count"].as_u64().unwrap_or_default() > 0)
+    }
+
+    // The blocks `uid` made and the ones made against them, paged with search_after
+    pub async fn blocks_involving(&self, uid: &str) -> Result<Vec<BlockRecord>, Error> {
+        let mut blocks: Vec<BlockRecord> = vec![];
+        loop {
+            let mut query = json!({
+                "size": BLOCK_PAGE_SIZE,
+                "query": {
+                    "bool": {
+                        "should": [
+                            { "term": { "blocker": uid } },
+                            { "term": { "blocked": uid } }
+                        ]
+                    }
+                },
+                "sort": [{ "id": "asc" }]
+            });
+            if let Some(last) = blocks.last() {
+                query["search_after"] = json!([last.id]);
+            }
+            let resp = self
+                .client
+                .search(SearchParts::Index(&[BlockIndex::name().as_str()]))
+                .body(query)
+                .send()
+                .await?
+                .error_for_status_code()?;
+            let json: Value = resp.json().await?;
+            let hits = json["hits"]["hits"].as_array().cloned().unwrap_or_default();
+            blocks.extend(
+                hits.iter()
+                    .filter_map(|h| serde_json::from_value(h["_source"].clone()).ok()),
+            );
+            if hits.len() < BLOCK_PAGE_SIZE {
+                return Ok(blocks);
+            }
+        }
+    }
+
+    pub async fn delete_blocks(&self, uid: &str) -> Result<(), Error> {
+        info!("Deleting blocks of User: {}", uid);
+        loop {
+            let resp = self
+                .client
+                .delete_by_query(DeleteByQueryParts::Index(&[BlockIndex::name().as_str()]))
+                .conflicts(Conflicts::Proceed)
+                .refresh(true)
+                .body(json!({
+                    "query": {
+                        "bool": {
+                            "should": [
+                                { "term": { "blocker": uid } },
+                                { "term": { "blocked": uid } }
+                            ]
+                        }
+                    }
+                }))
+                .send()
+                .await?
+                .error_for_status_code()?;
+            let json: Value = resp.json().await?;
+            if json["version_conflicts"].as_u64().unwrap_or_default() == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn other_side_of_the_block() {
+        let block = BlockRecord::new("a", "b", 0);
+        assert_eq!(block.id, "a_b");
+        assert_eq!(block.other("a"), "b");
+        assert_eq!(block.other("b"), "a");
+    }
+}
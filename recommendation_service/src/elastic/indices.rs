@@ -52,8 +52,110 @@ impl<'a> UserIndex<'a> {
                     "age" : { "type" : "integer" },
                     "location": {"type" : "geo_point" },
                     "gender": {"type": "integer"},
-                    "my_swipes": { "type": "text" },
-                    "potential_match": { "type": "text" }
+                    "preferences": Self::preferences_mapping()
+                }
+            }
+        })
+    }
+
+    // Also put on indices created before users had preferences
+    pub fn preferences_mapping() -> Value {
+        json!({
+            "properties": {
+                "genders": { "type": "integer" },
+                "min_age": { "type": "integer" },
+                "max_age": { "type": "integer" },
+                "max_distance": { "type": "integer" }
+            }
+        })
+    }
+}
+
+pub struct SwipeIndex;
+
+impl SwipeIndex {
+    pub fn name() -> String {
+        String::from("swipe_index")
+    }
+
+    // One document per (swiper, swipee), keyed "{swiper}_{swipee}"
+    pub fn body() -> Value {
+        json!({
+            "mappings" : {
+                "properties" : {
+                    "swiper": { "type": "keyword" },
+                    "swipee": { "type": "keyword" },
+                    "swipe": { "type": "integer" },
+                    "swiped_at": { "type": "date", "format": "epoch_millis" },
+                    "recorded_at": { "type": "date", "format": "epoch_millis" },
+                    "previous": { "type": "object", "enabled": false }
+                }
+            }
+        })
+    }
+}
+
+pub struct MatchIndex;
+
+impl MatchIndex {
+    pub fn name() -> String {
+        String::from("match_index")
+    }
+
+    // One document per pair of users, keyed by both uids in order
+    pub fn body() -> Value {
+        json!({
+            "mappings" : {
+                "properties" : {
+                    "id": { "type": "keyword" },
+                    "users": { "type": "keyword" },
+                    "matched_at": { "type": "date", "format": "epoch_millis" }
+                }
+            }
+        })
+    }
+}
+
+pub struct BlockIndex;
+
+impl BlockIndex {
+    pub fn name() -> String {
+        String::from("block_index")
+    }
+
+    // One document per (blocker, blocked), keyed "{blocker}_{blocked}"
+    pub fn body() -> Value {
+        json!({
+            "mappings" : {
+                "properties" : {
+                    "id": { "type": "keyword" },
+                    "blocker": { "type": "keyword" },
+                    "blocked": { "type": "keyword" },
+                    "blocked_at": { "type": "date", "format": "epoch_millis" }
+                }
+            }
+        })
+    }
+}
+
+pub struct ReportIndex;
+
+impl ReportIndex {
+    pub fn name() -> String {
+        String::from("report_index")
+    }
+
+    // The moderation queue, reports stay when either user is deleted
+    pub fn body() -> Value {
+        json!({
+            "mappings" : {
+                "properties" : {
+                    "reporter": { "type": "keyword" },
+                    "reported": { "type": "keyword" },
+                    "reason": { "type": "integer" },
+                    "details": { "type": "text" },
+                    "reported_at": { "type": "date", "format": "epoch_millis" },
+                    "status": { "type": "keyword" }
                 }
             }
         })
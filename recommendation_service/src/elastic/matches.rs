@@ -0,0 +1,169 @@
+use log::info;
+
+use super::indices::MatchIndex;
+use super::ops::ElasticOperator;
+use elasticsearch::indices::IndicesCreateParts;
+use elasticsearch::params::Conflicts;
+use elasticsearch::{
+    CreateParts, DeleteByQueryParts, DeleteParts, Elasticsearch, Error, SearchParts,
+};
+use serde_json::value::Value;
+
+// Two users who swiped right on each other, `matched_at` in epoch milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchRecord {
+    pub id: String,
+    pub users: Vec<String>,
+    pub matched_at: u64,
+}
+
+impl MatchRecord {
+    pub fn new(a: &str, b: &str, matched_at: u64) -> Self {
+        let mut users = vec![a.to_string(), b.to_string()];
+        users.sort();
+        Self {
+            id: match_id(a, b),
+            users,
+            matched_at,
+        }
+    }
+
+    // The user `uid` matched with
+    pub fn other(&self, uid: &str) -> &str {
+        self.users
+            .iter()
+            .find(|user| *user != uid)
+            .map(|user| user.as_str())
+            .unwrap_or_default()
+    }
+}
+
+// The same whichever of the two swiped last
+fn match_id(a: &str, b: &str) -> String {
+    if a < b {
+        format!("{}_{}", a, b)
+    } else {
+        format!("{}_{}", b, a)
+    }
+}
+
+pub async fn build_match_index(client: &Elasticsearch) {
+    info!("Building Match Index: {}", MatchIndex::name());
+    let response = client
+        .indices()
+        .create(IndicesCreateParts::Index(MatchIndex::name().as_str()))
+        .body(MatchIndex::body())
+        .send()
+        .await
+        .unwrap();
+    info!(
+        "Sucess for match index {}: {}",
+        MatchIndex::name(),
+        response.status_code().is_success()
+    );
+}
+
+impl ElasticOperator {
+    // False when the pair already matched, so only one swipe announces it
+    pub async fn create_match(&self, record: &MatchRecord) -> Result<bool, Error> {
+        let resp = self
+            .client
+            .create(CreateParts::IndexId(
+                MatchIndex::name().as_str(),
+                &record.id,
+            ))
+            .body(record)
+            .send()
+            .await?;
+        if resp.status_code().as_u16() == 409 {
+            return Ok(false);
+        }
+        resp.error_for_status_code()?;
+        info!("Users {} matched", record.id);
+        Ok(true)
+    }
+
+    // False when the two weren't matched
+    pub async fn delete_match(&self, a: &str, b: &str) -> Result<bool, Error> {
+        let resp = self
+            .client
+            .delete(DeleteParts::IndexId(
+                MatchIndex::name().as_str(),
+                &match_id(a, b),
+            ))
+            .send()
+            .await?;
+        if resp.status_code().as_u16() == 404 {
+            return Ok(false);
+        }
+        resp.error_for_status_code()?;
+        Ok(true)
+    }
+
+    // Newest first, `after` is the (matched_at, id) of the last match of the previous page
+    pub async fn matches_of(
+        &self,
+        uid: &str,
+        size: usize,
+        after: Option<(u64, String)>,
+    ) -> Result<Vec<MatchRecord>, Error> {
+        let mut query = json!({
+            "size": size,
+            "query": { "term": { "users": uid } },
+            "sort": [{ "matched_at": "desc" }, { "id": "asc" }]
+        });
+        if let Some((matched_at, id)) = after {
+            query["search_after"] = json!([matched_at, id]);
+        }
+        let resp = self
+            .client
+            .search(SearchParts::Index(&[MatchIndex::name().as_str()]))
+            .body(query)
+            .send()
+            .await?
+            .error_for_status_code()?;
+        let json: Value = resp.json().await?;
+        Ok(json["hits"]["hits"]
+            .as_array()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|h| serde_json::from_value(h["_source"].clone()).ok())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    pub async fn delete_matches(&self, uid: &str) -> Result<(), Error> {
+        info!("Deleting matches of User: {}", uid);
+        loop {
+            let resp = self
+                .client
+                .delete_by_query(DeleteByQueryParts::Index(&[MatchIndex::name().as_str()]))
+                .conflicts(Conflicts::Proceed)
+                .refresh(true)
+                .body(json!({ "query": { "term": { "users": uid } } }))
+                .send()
+                .await?
+                .error_for_status_code()?;
+            let json: Value = resp.json().await?;
+            if json["version_conflicts"].as_u64().unwrap_or_default() == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn same_match_from_either_side() {
+        let ab = MatchRecord::new("a", "b", 1);
+        let ba = MatchRecord::new("b", "a", 1);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.id, "a_b");
+        assert_eq!(ab.other("a"), "b");
+        assert_eq!(ab.other("b"), "a");
+    }
+}
@@ -0,0 +1,127 @@
+use log::{info, warn};
+
+use super::indices::UserIndex;
+use super::ops::{ElasticOperator, USER_INDEX_PATTERN};
+use elasticsearch::indices::IndicesPutMappingParts;
+use elasticsearch::params::Conflicts;
+use elasticsearch::{Error, GetParts, IndexParts, UpdateByQueryParts};
+use serde_json::value::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::delay_for;
+
+// Completed migrations, one document each
+pub const MIGRATION_INDEX: &str = "migration_index";
+pub const GENDER_MIGRATION: &str = "gender-unspecified";
+const MIGRATION_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+impl ElasticOperator {
+    pub async fn migration_done(&self, name: &str) -> Result<bool, Error> {
+        let resp = self
+            .client
+            .get(GetParts::IndexId(MIGRATION_INDEX, name))
+            .send()
+            .await?;
+        // Before the first migration the index doesn't exist either
+        if resp.status_code().as_u16() == 404 {
+            return Ok(false);
+        }
+        let json: Value = resp.error_for_status_code()?.json().await?;
+        Ok(json["found"].as_bool().unwrap_or_default())
+    }
+
+    // For writers that depend on `name`, returns once it's done
+    pub async fn wait_for_migration(&self, name: &str) {
+        loop {
+            match self.migration_done(name).await {
+                Ok(true) => return,
+                Ok(false) => info!("Waiting for migration {}", name),
+                Err(err) => warn!("Err checking migration {}: {}", name, err),
+            }
+            delay_for(MIGRATION_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn mark_migration_done(&self, name: &str) -> Result<(), Error> {
+        let completed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or_default();
+        self.client
+            .index(IndexParts::IndexId(MIGRATION_INDEX, name))
+            .body(json!({ "name": name, "completed_at": completed_at }))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        Ok(())
+    }
+
+    // User indices created before preferences existed lack their mapping,
+    // putting it again on the others is a no-op
+    pub async fn map_preferences(&self) -> Result<(), Error> {
+        info!("Mapping preferences on {}", USER_INDEX_PATTERN);
+        self.client
+            .indices()
+            .put_mapping(IndicesPutMappingParts::Index(&[USER_INDEX_PATTERN]))
+            .body(json!({
+                "properties": { "preferences": UserIndex::preferences_mapping() }
+            }))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        Ok(())
+    }
+
+    /*
+    Renumbers the genders of documents indexed before Gender had
+    Unspecified = 0: Male 0 -> 1 and Female 1 -> 2, preferences included.
+    Each document is tagged once renumbered so a pass can be rerun after a
+    failure. The server waits for it before writing with the new numbers,
+    which would be renumbered too, so run it once the previous release
+    stopped writing to the user indices.
+    */
+    pub async fn migrate_genders(&self) -> Result<(), Error> {
+        if self.migration_done(GENDER_MIGRATION).await? {
+            info!("Migration {} already done", GENDER_MIGRATION);
+            return Ok(());
+        }
+        loop {
+            let resp = self
+                .client
+                .update_by_query(UpdateByQueryParts::Index(&[USER_INDEX_PATTERN]))
+                .conflicts(Conflicts::Proceed)
+                .refresh(true)
+                .body(json!({
+                    "query": {
+                        "bool": {
+                            "must_not": { "exists": { "field": "gender_migrated" } }
+                        }
+                    },
+                    "script": {
+                        "lang": "painless",
+                        "source": "ctx._source.gender = params.genders.getOrDefault( \
+                                   String.valueOf(ctx._source.gender), 0); \
+                                   def preferences = ctx._source.preferences; \
+                                   if (preferences != null && preferences.genders != null) { \
+                                   def genders = []; \
+                                   for (g in preferences.genders) { \
+                                   genders.add(params.genders.getOrDefault(String.valueOf(g), 0)) } \
+                                   preferences.genders = genders } \
+                                   ctx._source.gender_migrated = true;",
+                        "params": { "genders": { "0": 1, "1": 2 } }
+                    }
+                }))
+                .send()
+                .await?
+                .error_for_status_code()?;
+            let json: Value = resp.json().await?;
+            info!(
+                "Renumbered genders of {} users",
+                json["updated"].as_u64().unwrap_or_default()
+            );
+            if json["version_conflicts"].as_u64().unwrap_or_default() == 0 {
+                break;
+            }
+        }
+        self.mark_migration_done(GENDER_MIGRATION).await
+    }
+}
@@ -1,2 +1,7 @@
+pub mod blocks;
+pub mod matches;
+pub mod migrations;
 pub mod ops;
+pub mod reports;
+pub mod swipes;
 mod indices;
\ No newline at end of file
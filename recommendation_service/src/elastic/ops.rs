@@ -1,14 +1,20 @@
 use log::{debug, error, info};
 
 use super::super::location::sharding::{GeoShard, MAX_SHARD};
-use super::super::recommendation::User;
+use super::super::recommendation::{Preferences, User};
 use super::indices::{GeoShardMappingIndex, UserIndex};
 use elasticsearch::indices::IndicesCreateParts;
 
 use serde_json::value::Value;
 
 use elasticsearch::http::request::JsonBody;
-use elasticsearch::{BulkParts, CreateParts, Elasticsearch, GetParts, SearchParts};
+use elasticsearch::{
+    BulkParts, CreateParts, DeleteParts, Elasticsearch, Error, GetParts, IndexParts, SearchParts,
+    UpdateParts,
+};
+
+// Matches every geosharded user index
+pub const USER_INDEX_PATTERN: &str = "geoshard_user_index_*";
 
 pub async fn build_geoshard_mapping_index(client: &Elasticsearch, shards: &[GeoShard]) {
     info!(
@@ -67,6 +73,113 @@ pub async fn build_geosharded_indices(client: &Elasticsearch, shards: &[GeoShard
     }
 }
 
+const METERS_PER_MILE: f64 = 1609.344;
+// Candidates read per queue search
+pub const QUEUE_PAGE_SIZE: usize = 1000;
+
+// What a queue request looks for and who is asking
+pub struct QueueQuery<'a> {
+    pub lat: f64,
+    pub lon: f64,
+    pub distance: u32, // miles
+    pub min_age: i32,
+    pub max_age: i32,
+    pub genders: &'a [i32], // any of them, empty for anyone
+    pub requester_age: i32,
+    pub requester_gender: i32,
+    pub exclude: &'a [String], // the requester
+    pub blocked: &'a [String],
+    pub boost: &'a [String], // ranked first
+    pub from: usize,         // candidates skipped, a multiple of QUEUE_PAGE_SIZE
+}
+
+// The search behind a queue, candidates matching the requester's filters
+// whose own preferences accept the requester
+pub fn queue_query(queue: &QueueQuery<'_>) -> Value {
+    let gender_filter = if queue.genders.is_empty() {
+        json!({ "match_all": {} })
+    } else {
+        json!({ "terms": { "gender": queue.genders } })
+    };
+    json!({
+      "from": queue.from, "size": QUEUE_PAGE_SIZE,
+      "query": {
+        "bool": {
+          "must": [
+            {
+              "range": {
+                "age": {
+                  "gte": queue.min_age,
+                  "lte": queue.max_age
+                }
+              }
+            }
+          ],
+          "must_not": [
+            { "ids": { "values": queue.exclude } },
+            { "ids": { "values": queue.blocked } }
+          ],
+          "should": [
+            { "ids": { "values": queue.boost, "boost": 10.0 } }
+          ],
+          "filter": [
+            gender_filter,
+            {
+              "geo_distance": {
+                "distance": format!("{}mi", queue.distance), // TODO: Metric support
+                "location": { "lon": queue.lon, "lat": queue.lat }
+              }
+            },
+            // The candidate's preferences have to accept the requester too,
+            // unset ones accept anyone
+            {
+              "bool": {
+                "minimum_should_match": 1,
+                "should": [
+                  { "term": { "preferences.genders": queue.requester_gender } },
+                  { "bool": { "must_not": { "exists": { "field": "preferences.genders" } } } }
+                ]
+              }
+            },
+            {
+              "bool": {
+                "minimum_should_match": 1,
+                "should": [
+                  { "range": { "preferences.min_age": { "lte": queue.requester_age } } },
+                  { "bool": { "must_not": { "exists": { "field": "preferences.min_age" } } } }
+                ]
+              }
+            },
+            {
+              "bool": {
+                "minimum_should_match": 1,
+                "should": [
+                  { "range": { "preferences.max_age": { "gte": queue.requester_age } } },
+                  { "term": { "preferences.max_age": 0 } },
+                  { "bool": { "must_not": { "exists": { "field": "preferences.max_age" } } } }
+                ]
+              }
+            },
+            // doc[] throws on indices without the field mapped, so check first
+            {
+              "script": {
+                "script": {
+                  "lang": "painless",
+                  "source": "if (!doc.containsKey('preferences.max_distance') || \
+                             doc['preferences.max_distance'].size() == 0) { return true; } \
+                             long max = doc['preferences.max_distance'].value; \
+                             return max == 0 || \
+                             doc['location'].arcDistance(params.lat, params.lon) <= max * params.mile;",
+                  "params": { "lat": queue.lat, "lon": queue.lon, "mile": METERS_PER_MILE }
+                }
+              }
+            }
+          ]
+        }
+      }
+    })
+}
+
 pub struct ElasticOperator {
     pub client: Elasticsearch,
 }
@@ -91,58 +204,31 @@ impl ElasticOperator {
     pub async fn get_users(
         &self,
         indices: Vec<&str>,
-        lat: f64,
-        lon: f64,
-        distance: u32,
-        age_range: Vec<i32>,
-        gender: u64,
-    ) -> Vec<User> {
-        let query = json!({
-          "from": 0, "size": 1000,
-          "query": {
-            "bool": {
-              "must": [
-                {
-                  "range": {
-                    "age": {
-                      "gte": age_range[0],
-                      "lte": age_range[1]
-                    }
-                  }
-                }
-              ],
-              "filter": [
-                {
-                  "term": {
-                    "gender": gender
-                  }
-                },
-                {
-                  "geo_distance": {
-                    "distance": format!("{}mi", distance), // TODO: Metric support
-                    "location": { "lon": lon, "lat": lat }
-                  }
-                }
-              ]
-            }
-          }
-        });
+        queue: &QueueQuery<'_>,
+    ) -> Result<Vec<User>, Error> {
+        let query = queue_query(queue);
         debug!("{}", query);
         let resp = self
             .client
             .search(SearchParts::Index(&indices.as_slice()))
             .body(query)
             .send()
-            .await
-            .unwrap();
-        let json: Value = resp.json().await.unwrap();
+            .await?
+            .error_for_status_code()?;
+        let json: Value = resp.json().await?;
         debug!("{}", json);
-        json["hits"]["hits"]
+        // Shards that failed leave their hits out rather than the whole response
+        if json["_shards"]["failed"].as_u64().unwrap_or_default() > 0 {
+            error!("Queue search failed on some shards: {}", json["_shards"]);
+        }
+        Ok(json["hits"]["hits"]
             .as_array()
-            .unwrap()
-            .iter()
-            .map(|h| serde_json::from_value(h["_source"].clone()).unwrap())
-            .collect()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|h| serde_json::from_value(h["_source"].clone()).ok())
+                    .collect()
+            })
+            .unwrap_or_default())
     }
 
     pub async fn write_user(&self, index: &str, user: User) {
@@ -164,6 +250,71 @@ impl ElasticOperator {
         println!("{:?}", resp);
     }
 
+    // Searches every shard since the caller doesn't know which one holds the user,
+    // returns the index the user was found in along with the document
+    pub async fn find_user(&self, uid: &str) -> Result<Option<(String, User)>, Error> {
+        let resp = self
+            .client
+            .search(SearchParts::Index(&[USER_INDEX_PATTERN]))
+            .body(json!({
+                "size": 1,
+                "query": { "ids": { "values": [uid] } }
+            }))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        let json: Value = resp.json().await?;
+        Ok(json["hits"]["hits"]
+            .as_array()
+            .and_then(|hits| hits.first())
+            .map(|hit| {
+                (
+                    hit["_index"].as_str().unwrap_or_default().to_string(),
+                    serde_json::from_value(hit["_source"].clone()).unwrap(),
+                )
+            }))
+    }
+
+    // Replaces only the preferences of the user's document
+    pub async fn set_preferences(
+        &self,
+        index: &str,
+        uid: &str,
+        preferences: &Preferences,
+    ) -> Result<(), Error> {
+        info!("Setting preferences of User: {} in {}", uid, index);
+        self.client
+            .update(UpdateParts::IndexId(index, uid))
+            .retry_on_conflict(3)
+            .body(json!({ "doc": { "preferences": preferences } }))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        Ok(())
+    }
+
+    // Creates or replaces the user's document in `index`
+    pub async fn index_user(&self, index: &str, user: &User) -> Result<(), Error> {
+        info!("Indexing User: {} into {}", user.uid, index);
+        self.client
+            .index(IndexParts::IndexId(index, &user.uid))
+            .body(user)
+            .send()
+            .await?
+            .error_for_status_code()?;
+        Ok(())
+    }
+
+    pub async fn delete_user(&self, index: &str, uid: &str) -> Result<(), Error> {
+        info!("Deleting User: {} from {}", uid, index);
+        self.client
+            .delete(DeleteParts::IndexId(index, uid))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        Ok(())
+    }
+
     pub async fn write_users(&self, user_body: Vec<JsonBody<serde_json::Value>>) {
         info!("Bulk writing users: {}", user_body.len() / 2);
         let resp = self
@@ -210,3 +361,62 @@ impl ElasticOperator {
         shards
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn queue<'a>(genders: &'a [i32], exclude: &'a [String]) -> QueueQuery<'a> {
+        QueueQuery {
+            lat: 1.0,
+            lon: 2.0,
+            distance: 10,
+            min_age: 20,
+            max_age: 30,
+            genders,
+            requester_age: 25,
+            requester_gender: 2,
+            exclude,
+            blocked: &[],
+            boost: &[],
+            from: 0,
+        }
+    }
+
+    #[test]
+    fn filters_on_both_sides_preferences() {
+        let exclude = vec!["me".to_string()];
+        let query = queue_query(&queue(&[1, 3], &exclude));
+        let filter = query["query"]["bool"]["filter"].as_array().unwrap();
+        assert_eq!(filter.len(), 6);
+        assert_eq!(filter[0], json!({ "terms": { "gender": [1, 3] } }));
+        assert_eq!(filter[1]["geo_distance"]["distance"], "10mi");
+        assert_eq!(
+            filter[2]["bool"]["should"][0],
+            json!({ "term": { "preferences.genders": 2 } })
+        );
+        assert_eq!(
+            filter[3]["bool"]["should"][0],
+            json!({ "range": { "preferences.min_age": { "lte": 25 } } })
+        );
+        assert_eq!(
+            filter[4]["bool"]["should"][0],
+            json!({ "range": { "preferences.max_age": { "gte": 25 } } })
+        );
+        let script = filter[5]["script"]["script"]["source"].as_str().unwrap();
+        assert!(script.starts_with("if (!doc.containsKey('preferences.max_distance')"));
+        assert_eq!(
+            query["query"]["bool"]["must_not"][0],
+            json!({ "ids": { "values": ["me"] } })
+        );
+    }
+
+    #[test]
+    fn no_genders_matches_anyone() {
+        let query = queue_query(&queue(&[], &[]));
+        assert_eq!(
+            query["query"]["bool"]["filter"][0],
+            json!({ "match_all": {} })
+        );
+    }
+}
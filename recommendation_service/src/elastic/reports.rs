@@ -0,0 +1,52 @@
+use log::info;
+
+use super::indices::ReportIndex;
+use super::ops::ElasticOperator;
+use elasticsearch::indices::IndicesCreateParts;
+use elasticsearch::{Elasticsearch, Error, IndexParts};
+
+// Reports start open and are closed by moderators
+pub const REPORT_OPEN: &str = "open";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReportRecord {
+    pub reporter: String,
+    pub reported: String,
+    pub reason: i32, // recommendation::ReportReason
+    pub details: String,
+    pub reported_at: u64, // epoch millis
+    pub status: String,
+}
+
+pub async fn build_report_index(client: &Elasticsearch) {
+    info!("Building Report Index: {}", ReportIndex::name());
+    let response = client
+        .indices()
+        .create(IndicesCreateParts::Index(ReportIndex::name().as_str()))
+        .body(ReportIndex::body())
+        .send()
+        .await
+        .unwrap();
+    info!(
+        "Sucess for report index {}: {}",
+        ReportIndex::name(),
+        response.status_code().is_success()
+    );
+}
+
+impl ElasticOperator {
+    // Queues the report for moderation, every report is kept
+    pub async fn file_report(&self, report: &ReportRecord) -> Result<(), Error> {
+        info!(
+            "User {} reported {} for {}",
+            report.reporter, report.reported, report.reason
+        );
+        self.client
+            .index(IndexParts::Index(ReportIndex::name().as_str()))
+            .body(report)
+            .send()
+            .await?
+            .error_for_status_code()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+use log::{debug, info};
+
+use super::indices::SwipeIndex;
+use super::ops::ElasticOperator;
+use elasticsearch::indices::IndicesCreateParts;
+use elasticsearch::params::{Conflicts, Refresh};
+use elasticsearch::{
+    DeleteByQueryParts, DeleteParts, Elasticsearch, Error, GetParts, IndexParts, SearchParts,
+};
+use serde_json::value::Value;
+use std::collections::HashSet;
+
+// Swipes read per search request when listing a user's swipes
+const SWIPE_PAGE_SIZE: usize = 1000;
+
+// What `swiper` decided on `swipee`, times in epoch milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SwipeRecord {
+    pub swiper: String,
+    pub swipee: String,
+    pub swipe: i32,       // recommendation::Swipe
+    pub swiped_at: u64,   // on the client's clock when it sent one
+    pub recorded_at: u64, // on the server's clock
+    // The decision this one replaced, restored when this one is undone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<PreviousSwipe>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PreviousSwipe {
+    pub swipe: i32,
+    pub swiped_at: u64,
+    pub recorded_at: u64,
+}
+
+impl SwipeRecord {
+    pub fn id(&self) -> String {
+        swipe_id(&self.swiper, &self.swipee)
+    }
+
+    // The record as it was before this decision, None when there was none
+    pub fn restored(&self) -> Option<SwipeRecord> {
+        self.previous.as_ref().map(|previous| SwipeRecord {
+            swiper: self.swiper.clone(),
+            swipee: self.swipee.clone(),
+            swipe: previous.swipe,
+            swiped_at: previous.swiped_at,
+            recorded_at: previous.recorded_at,
+            previous: None,
+        })
+    }
+}
+
+impl From<&SwipeRecord> for PreviousSwipe {
+    fn from(record: &SwipeRecord) -> Self {
+        Self {
+            swipe: record.swipe,
+            swiped_at: record.swiped_at,
+            recorded_at: record.recorded_at,
+        }
+    }
+}
+
+fn swipe_id(swiper: &str, swipee: &str) -> String {
+    format!("{}_{}", swiper, swipee)
+}
+
+pub async fn build_swipe_index(client: &Elasticsearch) {
+    info!("Building Swipe Index: {}", SwipeIndex::name());
+    let response = client
+        .indices()
+        .create(IndicesCreateParts::Index(SwipeIndex::name().as_str()))
+        .body(SwipeIndex::body())
+        .send()
+        .await
+        .unwrap();
+    info!(
+        "Sucess for swipe index {}: {}",
+        SwipeIndex::name(),
+        response.status_code().is_success()
+    );
+}
+
+impl ElasticOperator {
+    // Swiping on the same user again replaces the earlier decision. Waits
+    // for the swipe to be searchable, last_swipe must see it right away
+    pub async fn record_swipe(&self, swipe: &SwipeRecord) -> Result<(), Error> {
+        debug!("Recording swipe {:?}", swipe);
+        self.client
+            .index(IndexParts::IndexId(
+                SwipeIndex::name().as_str(),
+                &swipe.id(),
+            ))
+            .refresh(Refresh::WaitFor)
+            .body(swipe)
+            .send()
+            .await?
+            .error_for_status_code()?;
+        Ok(())
+    }
+
+    // Reads by id are realtime, a swipe is visible as soon as it was recorded
+    pub async fn get_swipe(
+        &self,
+        swiper: &str,
+        swipee: &str,
+    ) -> Result<Option<SwipeRecord>, Error> {
+        let resp = self
+            .client
+            .get(GetParts::IndexId(
+                SwipeIndex::name().as_str(),
+                &swipe_id(swiper, swipee),
+            ))
+            .send()
+            .await?;
+        if resp.status_code().as_u16() == 404 {
+            return Ok(None);
+        }
+        let json: Value = resp.error_for_status_code()?.json().await?;
+        Ok(serde_json::from_value(json["_source"].clone()).ok())
+    }
+
+    // Every swipe `swiper` made, paged with search_after so tens of
+    // thousands of them stay within the result window
+    pub async fn swipes_by(&self, swiper: &str) -> Result<Vec<SwipeRecord>, Error> {
+        let mut swipes: Vec<SwipeRecord> = vec![];
+        loop {
+            let mut query = json!({
+                "size": SWIPE_PAGE_SIZE,
+                "query": { "term": { "swiper": swiper } },
+                "sort": [{ "swipee": "asc" }]
+            });
+            if let Some(last) = swipes.last() {
+                query["search_after"] = json!([last.swipee]);
+            }
+            let resp = self
+                .client
+                .search(SearchParts::Index(&[SwipeIndex::name().as_str()]))
+                .body(query)
+                .send()
+                .await?
+                .error_for_status_code()?;
+            let json: Value = resp.json().await?;
+            let hits = json["hits"]["hits"].as_array().cloned().unwrap_or_default();
+            swipes.extend(
+                hits.iter()
+                    .filter_map(|h| serde_json::from_value(h["_source"].clone()).ok()),
+            );
+            if hits.len() < SWIPE_PAGE_SIZE {
+                return Ok(swipes);
+            }
+        }
+    }
+
+    // Which of `swipees` `swiper` already swiped on, a single terms query
+    // for at most QUEUE_PAGE_SIZE of them
+    pub async fn swiped_among(
+        &self,
+        swiper: &str,
+        swipees: &[String],
+    ) -> Result<HashSet<String>, Error> {
+        if swipees.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let resp = self
+            .client
+            .search(SearchParts::Index(&[SwipeIndex::name().as_str()]))
+            .body(json!({
+                "size": swipees.len(),
+                "_source": ["swipee"],
+                "query": {
+                    "bool": {
+                        "filter": [
+                            { "term": { "swiper": swiper } },
+                            { "terms": { "swipee": swipees } }
+                        ]
+                    }
+                }
+            }))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        let json: Value = resp.json().await?;
+        Ok(json["hits"]["hits"]
+            .as_array()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|h| h["_source"]["swipee"].as_str())
+                    .map(|swipee| swipee.to_string())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    // The swipe `swiper` recorded last, a search so only sees refreshed swipes
+    pub async fn last_swipe(&self, swiper: &str) -> Result<Option<SwipeRecord>, Error> {
+        let resp = self
+            .client
+            .search(SearchParts::Index(&[SwipeIndex::name().as_str()]))
+            .body(json!({
+                "size": 1,
+                "query": { "term": { "swiper": swiper } },
+                "sort": [{ "recorded_at": "desc" }]
+            }))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        let json: Value = resp.json().await?;
+        Ok(json["hits"]["hits"]
+            .as_array()
+            .and_then(|hits| hits.first())
+            .and_then(|hit| serde_json::from_value(hit["_source"].clone()).ok()))
+    }
+
+    // Up to `size` users whose swipe on `swipee` is of kind `swipe`
+    pub async fn swipers_of(
+        &self,
+        swipee: &str,
+        swipe: i32,
+        size: usize,
+    ) -> Result<Vec<String>, Error> {
+        let resp = self
+            .client
+            .search(SearchParts::Index(&[SwipeIndex::name().as_str()]))
+            .body(json!({
+                "size": size,
+                "_source": ["swiper"],
+                "query": {
+                    "bool": {
+                        "filter": [
+                            { "term": { "swipee": swipee } },
+                            { "term": { "swipe": swipe } }
+                        ]
+                    }
+                }
+            }))
+            .send()
+            .await?
+            .error_for_status_code()?;
+        let json: Value = resp.json().await?;
+        Ok(json["hits"]["hits"]
+            .as_array()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|h| h["_source"]["swiper"].as_str())
+                    .map(|swiper| swiper.to_string())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    // Waits like record_swipe, an undone swipe mustn't be undone again
+    pub async fn delete_swipe(&self, swiper: &str, swipee: &str) -> Result<(), Error> {
+        debug!("Deleting swipe of {} on {}", swiper, swipee);
+        let resp = self
+            .client
+            .delete(DeleteParts::IndexId(
+                SwipeIndex::name().as_str(),
+                &swipe_id(swiper, swipee),
+            ))
+            .refresh(Refresh::WaitFor)
+            .send()
+            .await?;
+        if resp.status_code().as_u16() != 404 {
+            resp.error_for_status_code()?;
+        }
+        Ok(())
+    }
+
+    // Removes the swipes `uid` made and the ones made on them, going again
+    // over documents that changed while they were deleted
+    pub async fn delete_swipes(&self, uid: &str) -> Result<(), Error> {
+        info!("Deleting swipes of User: {}", uid);
+        loop {
+            let resp = self
+                .client
+                .delete_by_query(DeleteByQueryParts::Index(&[SwipeIndex::name().as_str()]))
+                .conflicts(Conflicts::Proceed)
+                .refresh(true)
+                .body(json!({
+                    "query": {
+                        "bool": {
+                            "should": [
+                                { "term": { "swiper": uid } },
+                                { "term": { "swipee": uid } }
+                            ]
+                        }
+                    }
+                }))
+                .send()
+                .await?
+                .error_for_status_code()?;
+            let json: Value = resp.json().await?;
+            if json["version_conflicts"].as_u64().unwrap_or_default() == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn one_document_per_pair() {
+        let swipe = SwipeRecord {
+            swiper: "a".to_string(),
+            swipee: "b".to_string(),
+            swipe: 1,
+            swiped_at: 0,
+            recorded_at: 0,
+            previous: None,
+        };
+        assert_eq!(swipe.id(), "a_b");
+        assert_ne!(swipe.id(), swipe_id("b", "a"));
+    }
+
+    #[test]
+    fn restores_the_replaced_decision() {
+        let first = SwipeRecord {
+            swiper: "a".to_string(),
+            swipee: "b".to_string(),
+            swipe: 1,
+            swiped_at: 10,
+            recorded_at: 11,
+            previous: None,
+        };
+        assert_eq!(first.restored(), None);
+        let second = SwipeRecord {
+            swipe: 0,
+            swiped_at: 20,
+            recorded_at: 21,
+            previous: Some(PreviousSwipe::from(&first)),
+            ..first.clone()
+        };
+        assert_eq!(second.restored(), Some(first));
+    }
+}
@@ -0,0 +1,305 @@
+use super::recommendation::{Gender, Location, User};
+use super::service::MainRecommendactionService;
+use futures::channel::{mpsc, oneshot};
+use futures::executor::block_on;
+use futures::{SinkExt, StreamExt};
+use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+use log::{error, info, warn};
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+const DELIVERY_BUFFER: usize = 100;
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const FILE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Redeliveries arrive close to the original, remembering this many ids catches them
+const DEDUP_WINDOW: usize = 10000;
+
+/*
+User events published by the user service, see user_service/src/events.rs.
+Only the fields the recommendation service keeps are read, so the user
+service can add fields without breaking this consumer.
+*/
+#[derive(Debug, Clone, Deserialize)]
+pub struct Event {
+    pub id: String,
+    pub occurred_at: u64,
+    #[serde(flatten)]
+    pub payload: UserEvent,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub enum UserEvent {
+    UserCreated {
+        user: EventUser,
+    },
+    UserUpdated {
+        user: EventUser,
+    },
+    LocationChanged {
+        uid: String,
+        location: EventLocation,
+    },
+    UserDeleted {
+        uid: String,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventUser {
+    pub uid: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub age: i32,
+    pub gender: String,
+    pub location: EventLocation,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl From<EventLocation> for Location {
+    fn from(location: EventLocation) -> Self {
+        Location {
+            latitude: location.latitude,
+            longitude: location.longitude,
+        }
+    }
+}
+
+impl From<EventUser> for User {
+    fn from(user: EventUser) -> Self {
+        // Named like the user service's Gender, unknown names are Unspecified
+        let gender = match user.gender.as_str() {
+            "Male" => Gender::Male,
+            "Female" => Gender::Female,
+            "NonBinary" => Gender::NonBinary,
+            "Other" => Gender::Other,
+            _ => Gender::Unspecified,
+        };
+        User {
+            first_name: user.first_name,
+            last_name: user.last_name,
+            uid: user.uid,
+            age: user.age,
+            gender: gender as i32,
+            location: Some(user.location.into()),
+            preferences: None,
+        }
+    }
+}
+
+// An event and a way to tell its source it was applied
+pub struct Delivery {
+    pub event: Event,
+    ack: oneshot::Sender<()>,
+}
+
+impl Delivery {
+    pub fn ack(self) {
+        let _ = self.ack.send(());
+    }
+}
+
+/*
+Consumes `topic` as part of `group`. Offsets are only committed once
+every event of a fetched batch has been acked, so events are delivered
+at least once and a crash replays the unacked ones.
+*/
+pub fn kafka_events(
+    brokers: Vec<String>,
+    topic: String,
+    group: String,
+) -> Result<mpsc::Receiver<Delivery>, kafka::Error> {
+    let mut consumer = Consumer::from_hosts(brokers)
+        .with_topic(topic)
+        .with_group(group)
+        .with_fallback_offset(FetchOffset::Earliest)
+        .with_offset_storage(GroupOffsetStorage::Kafka)
+        .create()?;
+    let (mut sender, receiver) = mpsc::channel(DELIVERY_BUFFER);
+
+    // The kafka client is blocking so it gets its own thread
+    thread::spawn(move || loop {
+        let message_sets = match consumer.poll() {
+            Ok(message_sets) => message_sets,
+            Err(err) => {
+                error!("Err polling user events: {}", err);
+                thread::sleep(RETRY_INTERVAL);
+                continue;
+            }
+        };
+        for message_set in message_sets.iter() {
+            for message in message_set.messages() {
+                let event = match serde_json::from_slice::<Event>(message.value) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!("Skipping malformed user event: {}", err);
+                        continue;
+                    }
+                };
+                if !deliver(&mut sender, event) {
+                    return;
+                }
+            }
+            if let Err(err) = consumer.consume_messageset(message_set) {
+                error!("Err consuming user events: {}", err);
+            }
+        }
+        if let Err(err) = consumer.commit_consumed() {
+            error!("Err committing user event offsets: {}", err);
+        }
+    });
+    Ok(receiver)
+}
+
+/*
+Follows a file of JSON events, one per line, as written by the user
+service's FilePublisher. Every run starts from the top of the file.
+*/
+pub fn file_events(path: PathBuf) -> mpsc::Receiver<Delivery> {
+    let (mut sender, receiver) = mpsc::channel(DELIVERY_BUFFER);
+    thread::spawn(move || {
+        let file = loop {
+            match File::open(&path) {
+                Ok(file) => break file,
+                Err(_) => thread::sleep(FILE_POLL_INTERVAL),
+            }
+        };
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            match reader.read_line(&mut line) {
+                Ok(0) => thread::sleep(FILE_POLL_INTERVAL),
+                // A line without its newline is still being written
+                Ok(_) if !line.ends_with('\n') => (),
+                Ok(_) => {
+                    match serde_json::from_str::<Event>(&line) {
+                        Ok(event) => {
+                            if !deliver(&mut sender, event) {
+                                return;
+                            }
+                        }
+                        Err(err) => warn!("Skipping malformed user event: {}", err),
+                    }
+                    line.clear();
+                }
+                Err(err) => {
+                    error!("Err reading user events: {}", err);
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+// Blocks until the event is acked, false once the consumer is gone
+fn deliver(sender: &mut mpsc::Sender<Delivery>, event: Event) -> bool {
+    let (ack, acked) = oneshot::channel();
+    if block_on(sender.send(Delivery { event, ack })).is_err() {
+        return false;
+    }
+    block_on(acked).is_ok()
+}
+
+// The last `capacity` event ids seen
+pub struct RecentIds {
+    capacity: usize,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            ids: HashSet::with_capacity(capacity),
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn insert(&mut self, id: String) {
+        if !self.ids.insert(id.clone()) {
+            return;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+/*
+Applies deliveries in order, retrying each until elastic accepts it.
+The user service publishes at least once, repeats of a recently
+applied event are acked without applying them again.
+*/
+pub async fn consume(
+    service: MainRecommendactionService,
+    mut deliveries: mpsc::Receiver<Delivery>,
+) {
+    let mut applied = RecentIds::new(DEDUP_WINDOW);
+    while let Some(delivery) = deliveries.next().await {
+        if applied.contains(&delivery.event.id) {
+            info!("Skipping repeated user event {}", delivery.event.id);
+            delivery.ack();
+            continue;
+        }
+        while let Err(err) = service.apply_event(&delivery.event).await {
+            error!(
+                "Err applying user event {}, retrying: {}",
+                delivery.event.id, err
+            );
+            delay_for(RETRY_INTERVAL).await;
+        }
+        info!("Applied user event {}", delivery.event.id);
+        applied.insert(delivery.event.id.clone());
+        delivery.ack();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recent_ids_forget_the_oldest() {
+        let mut recent = RecentIds::new(2);
+        recent.insert("a".to_string());
+        recent.insert("b".to_string());
+        recent.insert("a".to_string());
+        assert!(recent.contains("a") && recent.contains("b"));
+        recent.insert("c".to_string());
+        assert!(!recent.contains("a"));
+        assert!(recent.contains("b") && recent.contains("c"));
+    }
+
+    #[test]
+    fn reads_user_service_events() {
+        let event: Event = serde_json::from_str(
+            r#"{"id":"1","occurred_at":2,"type":"UserCreated","user":{"uid":"u","first_name":"F","last_name":"L","age":30,"gender":"Female","bio":"","location":{"latitude":1.5,"longitude":2.5}}}"#,
+        )
+        .unwrap();
+        let user: User = match event.payload {
+            UserEvent::UserCreated { user } => user.into(),
+            other => panic!("unexpected event {:?}", other),
+        };
+        assert_eq!(user.gender, Gender::Female as i32);
+        assert_eq!(user.location.unwrap().longitude, 2.5);
+    }
+}
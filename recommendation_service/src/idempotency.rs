@@ -0,0 +1,167 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/*
+Remembers the response to the last `capacity` keyed requests so a client
+retrying with the same idempotency key gets the first response back.
+A key is reserved before the request is handled, so a retry arriving
+while the first attempt still runs doesn't handle it a second time.
+Kept in memory, a restart or another instance starts empty.
+*/
+pub struct IdempotencyCache<T> {
+    capacity: usize,
+    entries: Mutex<(VecDeque<String>, HashMap<String, Entry<T>>)>,
+}
+
+struct Entry<T> {
+    fingerprint: u64,    // hash of the request the key was first sent with
+    response: Option<T>, // None while that request is handled
+}
+
+pub enum Reserved<'a, T: Clone> {
+    // First use of the key, complete the reservation with the response
+    New(Reservation<'a, T>),
+    Done(T),
+    InProgress,
+    // The key was sent with another request
+    Mismatch,
+}
+
+impl<T: Clone> IdempotencyCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new((VecDeque::with_capacity(capacity), HashMap::new())),
+        }
+    }
+
+    // Keys are scoped to the caller so users can't read each other's responses
+    pub fn reserve(&self, uid: &str, key: &str, fingerprint: u64) -> Reserved<T> {
+        let (order, entries) = &mut *self.entries.lock().unwrap();
+        let key = scoped(uid, key);
+        if let Some(entry) = entries.get(&key) {
+            if entry.fingerprint != fingerprint {
+                return Reserved::Mismatch;
+            }
+            return match &entry.response {
+                Some(response) => Reserved::Done(response.clone()),
+                None => Reserved::InProgress,
+            };
+        }
+        entries.insert(
+            key.clone(),
+            Entry {
+                fingerprint,
+                response: None,
+            },
+        );
+        order.push_back(key.clone());
+        if order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                entries.remove(&oldest);
+            }
+        }
+        Reserved::New(Reservation {
+            cache: self,
+            key,
+            completed: false,
+        })
+    }
+}
+
+fn scoped(uid: &str, key: &str) -> String {
+    format!("{}:{}", uid, key)
+}
+
+// Released when dropped uncompleted, a failed request can be retried with its key
+pub struct Reservation<'a, T: Clone> {
+    cache: &'a IdempotencyCache<T>,
+    key: String,
+    completed: bool,
+}
+
+impl<'a, T: Clone> Reservation<'a, T> {
+    pub fn complete(mut self, response: T) {
+        let (_, entries) = &mut *self.cache.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&self.key) {
+            entry.response = Some(response);
+        }
+        self.completed = true;
+    }
+}
+
+impl<'a, T: Clone> Drop for Reservation<'a, T> {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let (order, entries) = &mut *self.cache.entries.lock().unwrap();
+        if entries.remove(&self.key).is_some() {
+            order.retain(|key| key != &self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn done<T: Clone>(reserved: Reserved<T>) -> Option<T> {
+        match reserved {
+            Reserved::Done(response) => Some(response),
+            _ => None,
+        }
+    }
+
+    fn complete<T: Clone>(cache: &IdempotencyCache<T>, uid: &str, key: &str, response: T) {
+        match cache.reserve(uid, key, 0) {
+            Reserved::New(reservation) => reservation.complete(response),
+            _ => panic!("{} was already reserved", key),
+        }
+    }
+
+    #[test]
+    fn returns_first_response_per_caller() {
+        let cache = IdempotencyCache::new(10);
+        complete(&cache, "a", "key", true);
+        assert_eq!(done(cache.reserve("a", "key", 0)), Some(true));
+        assert!(matches!(cache.reserve("b", "key", 0), Reserved::New(_)));
+    }
+
+    #[test]
+    fn rejects_a_key_sent_with_another_request() {
+        let cache = IdempotencyCache::new(10);
+        complete(&cache, "a", "key", true);
+        assert!(matches!(cache.reserve("a", "key", 1), Reserved::Mismatch));
+    }
+
+    #[test]
+    fn holds_the_key_until_the_first_request_is_done() {
+        let cache = IdempotencyCache::new(10);
+        let reservation = match cache.reserve("a", "key", 0) {
+            Reserved::New(reservation) => reservation,
+            _ => panic!("key was already reserved"),
+        };
+        assert!(matches!(cache.reserve("a", "key", 0), Reserved::InProgress));
+        reservation.complete(true);
+        assert_eq!(done(cache.reserve("a", "key", 0)), Some(true));
+    }
+
+    #[test]
+    fn releases_the_key_of_a_failed_request() {
+        let cache: IdempotencyCache<bool> = IdempotencyCache::new(10);
+        drop(cache.reserve("a", "key", 0));
+        assert!(matches!(cache.reserve("a", "key", 1), Reserved::New(_)));
+    }
+
+    #[test]
+    fn forgets_the_oldest() {
+        let cache = IdempotencyCache::new(2);
+        complete(&cache, "a", "1", 1);
+        complete(&cache, "a", "2", 2);
+        complete(&cache, "a", "3", 3);
+        assert_eq!(done(cache.reserve("a", "2", 0)), Some(2));
+        assert_eq!(done(cache.reserve("a", "3", 0)), Some(3));
+        assert!(matches!(cache.reserve("a", "1", 0), Reserved::New(_)));
+    }
+}
@@ -4,7 +4,13 @@ extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 
+pub mod auth;
 pub mod elastic;
+pub mod events;
+pub mod idempotency;
 pub mod location;
+pub mod quota;
 pub mod recommendation;
-pub mod service;
\ No newline at end of file
+pub mod service;
+pub mod subscriptions;
+pub mod user;
\ No newline at end of file
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Expired counters are dropped once the in-memory store holds this many
+const PRUNE_THRESHOLD: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuotaKind {
+    RightSwipes,
+    SuperLikes,
+    QueueRequests,
+}
+
+impl QuotaKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            QuotaKind::RightSwipes => "right_swipes",
+            QuotaKind::SuperLikes => "super_likes",
+            QuotaKind::QueueRequests => "queue_requests",
+        }
+    }
+}
+
+// At most `limit` uses per `window`, windows start at multiples of their length
+// since the epoch so a day is a UTC day
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub limit: u64,
+    pub window: Duration,
+}
+
+impl Quota {
+    pub fn per_day(limit: u64) -> Self {
+        Self {
+            limit,
+            window: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    pub fn per_minute(limit: u64) -> Self {
+        Self {
+            limit,
+            window: Duration::from_secs(60),
+        }
+    }
+
+    // When the window `now` falls in ends
+    pub fn resets_at(&self, now: SystemTime) -> SystemTime {
+        let window = self.window.as_secs().max(1);
+        let since = now
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        UNIX_EPOCH + Duration::from_secs(since - since % window + window)
+    }
+}
+
+#[derive(Clone)]
+pub struct Quotas {
+    pub right_swipes: Quota,
+    pub super_likes: Quota,
+    pub queue_requests: Quota,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Self {
+            right_swipes: Quota::per_day(100),
+            super_likes: Quota::per_day(1),
+            queue_requests: Quota::per_minute(30),
+        }
+    }
+}
+
+impl Quotas {
+    // RIGHT_SWIPES_PER_DAY, SUPER_LIKES_PER_DAY and QUEUE_REQUESTS_PER_MINUTE,
+    // the defaults otherwise
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            right_swipes: env_number("RIGHT_SWIPES_PER_DAY")
+                .map(Quota::per_day)
+                .unwrap_or(defaults.right_swipes),
+            super_likes: env_number("SUPER_LIKES_PER_DAY")
+                .map(Quota::per_day)
+                .unwrap_or(defaults.super_likes),
+            queue_requests: env_number("QUEUE_REQUESTS_PER_MINUTE")
+                .map(Quota::per_minute)
+                .unwrap_or(defaults.queue_requests),
+        }
+    }
+
+    pub fn get(&self, kind: QuotaKind) -> Quota {
+        match kind {
+            QuotaKind::RightSwipes => self.right_swipes,
+            QuotaKind::SuperLikes => self.super_likes,
+            QuotaKind::QueueRequests => self.queue_requests,
+        }
+    }
+}
+
+pub(crate) fn env_number(name: &str) -> Option<u64> {
+    env::var(name).ok().map(|value| match value.parse::<u64>() {
+        Ok(number) => number,
+        Err(_) => panic!("{} must be a number: {}", name, value),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Taken {
+    Allowed,
+    Exhausted { resets_at: SystemTime },
+}
+
+/*
+Counts uses of a quota per key. The in-memory store only counts what
+reaches this instance, a store shared between instances can be plugged
+in through MainRecommendactionService::with_quota_store.
+*/
+#[tonic::async_trait]
+pub trait QuotaStore: Send + Sync {
+    // Counts one use unless the key's quota is used up
+    async fn take(
+        &self,
+        key: &str,
+        quota: Quota,
+        now: SystemTime,
+    ) -> Result<Taken, Box<dyn Error + Send + Sync>>;
+
+    // Returns a use taken at `taken_at`, unless its window has reset since
+    async fn give_back(
+        &self,
+        key: &str,
+        quota: Quota,
+        taken_at: SystemTime,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+#[derive(Default)]
+pub struct MemoryQuotaStore {
+    counters: Mutex<HashMap<String, (SystemTime, u64)>>, // key -> (resets_at, used)
+}
+
+#[tonic::async_trait]
+impl QuotaStore for MemoryQuotaStore {
+    async fn take(
+        &self,
+        key: &str,
+        quota: Quota,
+        now: SystemTime,
+    ) -> Result<Taken, Box<dyn Error + Send + Sync>> {
+        let resets_at = quota.resets_at(now);
+        let mut counters = self.counters.lock().unwrap();
+        if counters.len() >= PRUNE_THRESHOLD {
+            counters.retain(|_, (resets_at, _)| *resets_at > now);
+        }
+        let counter = counters.entry(key.to_string()).or_insert((resets_at, 0));
+        if counter.0 <= now {
+            *counter = (resets_at, 0);
+        }
+        if counter.1 >= quota.limit {
+            return Ok(Taken::Exhausted {
+                resets_at: counter.0,
+            });
+        }
+        counter.1 += 1;
+        Ok(Taken::Allowed)
+    }
+
+    async fn give_back(
+        &self,
+        key: &str,
+        quota: Quota,
+        taken_at: SystemTime,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut counters = self.counters.lock().unwrap();
+        if let Some(counter) = counters.get_mut(key) {
+            if counter.0 == quota.resets_at(taken_at) {
+                counter.1 = counter.1.saturating_sub(1);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn windows_are_aligned() {
+        let quota = Quota::per_minute(1);
+        assert_eq!(quota.resets_at(at(0)), at(60));
+        assert_eq!(quota.resets_at(at(59)), at(60));
+        assert_eq!(quota.resets_at(at(60)), at(120));
+    }
+
+    #[test]
+    fn exhausts_until_the_window_resets() {
+        let store = MemoryQuotaStore::default();
+        let quota = Quota::per_minute(2);
+        let take = |key, secs| block_on(store.take(key, quota, at(secs))).unwrap();
+        assert_eq!(take("a", 0), Taken::Allowed);
+        assert_eq!(take("a", 10), Taken::Allowed);
+        assert_eq!(take("a", 20), Taken::Exhausted { resets_at: at(60) });
+        assert_eq!(take("b", 20), Taken::Allowed);
+        assert_eq!(take("a", 60), Taken::Allowed);
+    }
+
+    #[test]
+    fn gives_back_within_the_window() {
+        let store = MemoryQuotaStore::default();
+        let quota = Quota::per_minute(1);
+        let take = |secs| block_on(store.take("a", quota, at(secs))).unwrap();
+        let give_back = |secs| block_on(store.give_back("a", quota, at(secs))).unwrap();
+        assert_eq!(take(0), Taken::Allowed);
+        give_back(0);
+        assert_eq!(take(10), Taken::Allowed);
+        assert_eq!(take(20), Taken::Exhausted { resets_at: at(60) });
+        assert_eq!(take(60), Taken::Allowed);
+        // Taken in the window before, the current one stays used up
+        give_back(10);
+        assert_eq!(take(70), Taken::Exhausted { resets_at: at(120) });
+    }
+
+    #[test]
+    fn zero_limit_allows_nothing() {
+        let store = MemoryQuotaStore::default();
+        let taken = block_on(store.take("a", Quota::per_day(0), at(0))).unwrap();
+        assert_eq!(
+            taken,
+            Taken::Exhausted {
+                resets_at: at(24 * 60 * 60)
+            }
+        );
+    }
+}
@@ -1,21 +1,100 @@
-use super::elastic::ops::ElasticOperator;
+use super::auth::{authenticated_uid, RevocationCheck};
+use super::elastic::blocks::BlockRecord;
+use super::elastic::matches::MatchRecord;
+use super::elastic::ops::{ElasticOperator, QueueQuery, QUEUE_PAGE_SIZE};
+use super::elastic::reports::{ReportRecord, REPORT_OPEN};
+use super::elastic::swipes::{PreviousSwipe, SwipeRecord};
+use super::events::{Event, UserEvent};
+use super::idempotency::{IdempotencyCache, Reserved};
+use super::quota::{env_number, MemoryQuotaStore, QuotaKind, QuotaStore, Quotas, Taken};
 use super::recommendation::{
-    recommendation_service_server::RecommendationService, GetQueueRequest, SwipeRequest,
-    SwipeResponse, User,
+    recommendation_service_server::RecommendationService, BlockUserRequest, BlockUserResponse,
+    ExportUserDataRequest, ExportUserDataResponse, Gender, GetPreferencesRequest, GetQueueRequest,
+    ListMatchesRequest, ListMatchesResponse, Location, Match, Preferences, ReportReason,
+    ReportUserRequest, ReportUserResponse, SetPreferencesRequest, SubscribeMatchesRequest, Swipe,
+    SwipeRequest, SwipeResponse, SwipeV2Request, UndoLastSwipeRequest, UndoLastSwipeResponse,
+    UnmatchRequest, UnmatchResponse, UpdateLocationRequest, UpdateLocationResponse, User,
 };
+use super::subscriptions::{MatchStream, MatchSubscriptions};
 use futures::{
     task::{Context, Poll},
     Stream,
 };
-use log::info;
+use log::{error, info, warn};
+use prost_types::Timestamp;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::pin::Pin;
-use tonic::{Request, Response, Status};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::{Code, Request, Response, Status};
 
 use super::location::sharding::GeoShardSearcher;
 
+// Swipe responses remembered for clients retrying with an idempotency key
+const SWIPE_KEYS_CAPACITY: usize = 10000;
+// How far ahead of the server a client clock may be
+const MAX_CLIENT_SKEW: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_MATCH_PAGE_SIZE: u32 = 50;
+const MAX_MATCH_PAGE_SIZE: u32 = 100;
+// Matches read per search request when exporting them all
+const EXPORT_MATCH_PAGE_SIZE: usize = 1000;
+// Super likers ranked first in a queue
+const BOOSTED_QUEUE_SIZE: usize = 100;
+// Users returned by a queue request
+const QUEUE_SIZE: usize = 100;
+// How deep into the candidates a queue request goes looking for users not
+// swiped on yet, the default result window of an index
+const MAX_QUEUE_CANDIDATES: usize = 10_000;
+const MAX_REPORT_DETAILS_LENGTH: usize = 2000;
+const MIN_AGE: i32 = 18;
+const MAX_AGE: i32 = 120;
+// Miles, for queues without a radius from the request or preferences
+const DEFAULT_QUEUE_RADIUS: u32 = 50;
+const MAX_PREFERRED_DISTANCE: u32 = 500;
+// Set on RESOURCE_EXHAUSTED, when the quota is available again in unix seconds
+pub const QUOTA_RESET_METADATA: &str = "x-quota-reset";
+// Set on RESOURCE_EXHAUSTED, seconds until the quota is available again
+pub const RETRY_AFTER_METADATA: &str = "retry-after";
+
+#[derive(Clone)]
+pub struct SwipeLimits {
+    pub undo_window: Duration, // how long after a swipe it can be undone
+}
+
+impl Default for SwipeLimits {
+    fn default() -> Self {
+        Self {
+            undo_window: Duration::from_secs(60),
+        }
+    }
+}
+
+impl SwipeLimits {
+    // UNDO_WINDOW_SEC, the default otherwise
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            undo_window: env_number("UNDO_WINDOW_SEC")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.undo_window),
+        }
+    }
+}
+
+// Clones share the shards, caches and quota counters of the original, so the
+// event consumer and the server run on one service
+#[derive(Clone)]
 pub struct MainRecommendactionService {
-    elastic_operator: ElasticOperator,
-    searcher: GeoShardSearcher,
+    elastic_operator: Arc<ElasticOperator>,
+    searcher: Arc<GeoShardSearcher>,
+    swipe_keys: Arc<IdempotencyCache<SwipeResponse>>,
+    match_subscriptions: Arc<MatchSubscriptions>,
+    limits: SwipeLimits,
+    quotas: Quotas,
+    quota_store: Arc<dyn QuotaStore>,
+    revocations: Option<Arc<RevocationCheck>>,
 }
 
 impl MainRecommendactionService {
@@ -23,8 +102,91 @@ impl MainRecommendactionService {
         let shards = elastic_operator.load_shard_into_memory().await;
         let searcher = GeoShardSearcher::from(shards);
         Self {
-            elastic_operator,
-            searcher,
+            elastic_operator: Arc::new(elastic_operator),
+            searcher: Arc::new(searcher),
+            swipe_keys: Arc::new(IdempotencyCache::new(SWIPE_KEYS_CAPACITY)),
+            match_subscriptions: Arc::new(MatchSubscriptions::default()),
+            limits: SwipeLimits::default(),
+            quotas: Quotas::default(),
+            quota_store: Arc::new(MemoryQuotaStore::default()),
+            revocations: None,
+        }
+    }
+
+    pub fn with_swipe_limits(mut self, limits: SwipeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    pub fn with_quota_store(mut self, quota_store: Box<dyn QuotaStore>) -> Self {
+        self.quota_store = quota_store.into();
+        self
+    }
+
+    // Without it revoked tokens are accepted until they expire
+    pub fn with_revocation_check(mut self, revocations: RevocationCheck) -> Self {
+        self.revocations = Some(Arc::new(revocations));
+        self
+    }
+
+    // The uid the interceptor took from the token, once it's known not to be revoked
+    async fn caller<T>(&self, request: &Request<T>) -> Result<String, Status> {
+        let uid = authenticated_uid(request)?;
+        if let Some(revocations) = &self.revocations {
+            revocations.check(request).await?;
+        }
+        Ok(uid)
+    }
+
+    // Request bodies still carry a uid, it may only name the caller
+    async fn caller_uid<T>(&self, request: &Request<T>, claimed: &str) -> Result<String, Status> {
+        let uid = self.caller(request).await?;
+        if !claimed.is_empty() && claimed != uid {
+            return Err(Status::permission_denied("uid is not the caller's"));
+        }
+        Ok(uid)
+    }
+
+    /*
+    Counts one use of the caller's quota, RESOURCE_EXHAUSTED once it's
+    used up. A failing store lets the request through rather than taking
+    the RPC down with it.
+    */
+    async fn take_quota(&self, uid: &str, kind: QuotaKind) -> Result<(), Status> {
+        let key = format!("{}:{}", kind.name(), uid);
+        let now = SystemTime::now();
+        match self
+            .quota_store
+            .take(&key, self.quotas.get(kind), now)
+            .await
+        {
+            Ok(Taken::Allowed) => Ok(()),
+            Ok(Taken::Exhausted { resets_at }) => {
+                info!("User {} used up their {} quota", uid, kind.name());
+                Err(quota_exhausted(kind, resets_at, now))
+            }
+            Err(err) => {
+                error!("Quota store err for {}: {}", key, err);
+                Ok(())
+            }
+        }
+    }
+
+    // Returns a use of the caller's quota taken at `taken_at`, a failing
+    // store keeps it
+    async fn give_back_quota(&self, uid: &str, kind: QuotaKind, taken_at: SystemTime) {
+        let key = format!("{}:{}", kind.name(), uid);
+        if let Err(err) = self
+            .quota_store
+            .give_back(&key, self.quotas.get(kind), taken_at)
+            .await
+        {
+            error!("Quota store err for {}: {}", key, err);
         }
     }
 
@@ -34,17 +196,435 @@ impl MainRecommendactionService {
             self.elastic_operator.write_users(body).await;
         }
     }
+
+    // Into the shard of the user's location, failing rather than only logging
+    // so the event is delivered again
+    async fn index_new_user(&self, user: &User) -> Result<(), elasticsearch::Error> {
+        let location = user.location.clone().unwrap_or_default();
+        let shard = self
+            .searcher
+            .get_shard_from_lng_lat(location.longitude, location.latitude);
+        self.elastic_operator.index_user(&shard.name, user).await
+    }
+
+    /*
+    Brings the shard documents in line with a user service event. Events
+    can be delivered more than once so applying one again is harmless.
+    */
+    pub async fn apply_event(&self, event: &Event) -> Result<(), elasticsearch::Error> {
+        match event.payload.clone() {
+            UserEvent::UserCreated { user } => {
+                // Already indexed by an earlier delivery, keep its location and preferences
+                if self.elastic_operator.find_user(&user.uid).await?.is_none() {
+                    self.index_new_user(&user.into()).await?;
+                }
+            }
+            UserEvent::UserUpdated { user } => {
+                let updated: User = user.into();
+                match self.elastic_operator.find_user(&updated.uid).await? {
+                    // Location and preferences are owned by this service
+                    Some((index, stored)) => {
+                        let user = User {
+                            location: stored.location,
+                            preferences: stored.preferences,
+                            ..updated
+                        };
+                        self.elastic_operator.index_user(&index, &user).await?;
+                    }
+                    // Deleted since, or not created yet, UserCreated carries the whole user
+                    None => info!("Ignoring update of unknown user {}", updated.uid),
+                }
+            }
+            UserEvent::LocationChanged { uid, location } => {
+                if self.move_user(&uid, location.into()).await?.is_none() {
+                    info!("Ignoring location of unknown user {}", uid);
+                }
+            }
+            UserEvent::UserDeleted { uid } => {
+                if let Some((index, _)) = self.elastic_operator.find_user(&uid).await? {
+                    self.elastic_operator.delete_user(&index, &uid).await?;
+                }
+                self.elastic_operator.delete_swipes(&uid).await?;
+                self.elastic_operator.delete_matches(&uid).await?;
+                self.elastic_operator.delete_blocks(&uid).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // The shard the user now lives in and whether they changed shard,
+    // None when there is no such user
+    async fn move_user(
+        &self,
+        uid: &str,
+        location: Location,
+    ) -> Result<Option<(String, bool)>, elasticsearch::Error> {
+        let (old_index, mut user) = match self.elastic_operator.find_user(uid).await? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let new_shard = self
+            .searcher
+            .get_shard_from_lng_lat(location.longitude, location.latitude);
+        user.location = Some(location);
+
+        // The whole document moves, preferences included. Writing before
+        // deleting means a failure leaves a duplicate rather than losing the user.
+        self.elastic_operator
+            .index_user(&new_shard.name, &user)
+            .await?;
+        let moved = new_shard.name != old_index;
+        if moved {
+            info!(
+                "User {} moved from {} to {}",
+                user.uid, old_index, new_shard.name
+            );
+            self.elastic_operator
+                .delete_user(&old_index, &user.uid)
+                .await?;
+        }
+        Ok(Some((new_shard.name.clone(), moved)))
+    }
+
+    /*
+    Records `swiper_uid` swiping on `swipee_uid` at `swiped_at`, swiping
+    again replaces the earlier decision. The swipe is written before the
+    other way round is read, so when both swipe right at the same time at
+    least one of them sees the match.
+    */
+    async fn record_swipe(
+        &self,
+        swiper_uid: &str,
+        swipee_uid: &str,
+        swipe: Swipe,
+        swiped_at: SystemTime,
+    ) -> Result<SwipeResponse, Status> {
+        if swipee_uid.is_empty() {
+            return Err(Status::invalid_argument("please provide swipee_uid"));
+        }
+        if swiper_uid == swipee_uid {
+            return Err(Status::invalid_argument("users can't swipe on themselves"));
+        }
+        // Blocked users look the same as missing ones
+        if self
+            .elastic_operator
+            .find_user(swipee_uid)
+            .await
+            .map_err(internal)?
+            .is_none()
+            || self
+                .elastic_operator
+                .is_blocked(swiper_uid, swipee_uid)
+                .await
+                .map_err(internal)?
+        {
+            return Err(Status::not_found("swipee not found"));
+        }
+
+        if let Some(kind) = swipe_quota(swipe as i32) {
+            self.take_quota(swiper_uid, kind).await?;
+        }
+        // Kept so undoing this swipe brings the earlier decision back
+        let previous = self
+            .elastic_operator
+            .get_swipe(swiper_uid, swipee_uid)
+            .await
+            .map_err(internal)?;
+        let now = millis(SystemTime::now());
+        let record = SwipeRecord {
+            swiper: swiper_uid.to_string(),
+            swipee: swipee_uid.to_string(),
+            swipe: swipe as i32,
+            swiped_at: millis(swiped_at),
+            recorded_at: now,
+            previous: previous.as_ref().map(PreviousSwipe::from),
+        };
+        self.elastic_operator
+            .record_swipe(&record)
+            .await
+            .map_err(internal)?;
+        let matched = likes(swipe as i32)
+            && match self
+                .elastic_operator
+                .get_swipe(swipee_uid, swiper_uid)
+                .await
+                .map_err(internal)?
+            {
+                Some(theirs) => likes(theirs.swipe),
+                None => false,
+            };
+        info!(
+            "User {} swiped {:?} on {}, match: {}",
+            swiper_uid, swipe, swipee_uid, matched
+        );
+        if matched {
+            self.announce_match(swiper_uid, swipee_uid).await?;
+        }
+        Ok(SwipeResponse { r#match: matched })
+    }
+
+    // Hides the two users from each other and dissolves their match
+    async fn block(&self, blocker: &str, blocked: &str) -> Result<(), Status> {
+        if blocked.is_empty() {
+            return Err(Status::invalid_argument("please provide uid"));
+        }
+        if blocker == blocked {
+            return Err(Status::invalid_argument("users can't block themselves"));
+        }
+        if self
+            .elastic_operator
+            .find_user(blocked)
+            .await
+            .map_err(internal)?
+            .is_none()
+        {
+            return Err(Status::not_found("user not found"));
+        }
+        let record = BlockRecord::new(blocker, blocked, millis(SystemTime::now()));
+        self.elastic_operator
+            .block(&record)
+            .await
+            .map_err(internal)?;
+        self.elastic_operator
+            .delete_match(blocker, blocked)
+            .await
+            .map_err(internal)?;
+        Ok(())
+    }
+
+    // Stores the match and tells both users, unless the other swipe already did
+    async fn announce_match(&self, a: &str, b: &str) -> Result<(), Status> {
+        let record = MatchRecord::new(a, b, millis(SystemTime::now()));
+        if self
+            .elastic_operator
+            .create_match(&record)
+            .await
+            .map_err(internal)?
+        {
+            for uid in &record.users {
+                self.match_subscriptions.notify(uid, to_match(&record, uid));
+            }
+        }
+        Ok(())
+    }
+
+    async fn all_matches(&self, uid: &str) -> Result<Vec<MatchRecord>, elasticsearch::Error> {
+        let mut matches: Vec<MatchRecord> = vec![];
+        loop {
+            let after = matches
+                .last()
+                .map(|last| (last.matched_at, last.id.clone()));
+            let page = self
+                .elastic_operator
+                .matches_of(uid, EXPORT_MATCH_PAGE_SIZE, after)
+                .await?;
+            let done = page.len() < EXPORT_MATCH_PAGE_SIZE;
+            matches.extend(page);
+            if done {
+                return Ok(matches);
+            }
+        }
+    }
+}
+
+// The match as `uid` sees it
+fn to_match(record: &MatchRecord, uid: &str) -> Match {
+    Match {
+        uid: record.other(uid).to_string(),
+        matched_at: Some(Timestamp {
+            seconds: (record.matched_at / 1000) as i64,
+            nanos: ((record.matched_at % 1000) * 1_000_000) as i32,
+        }),
+    }
+}
+
+// Page tokens are "{matched_at}:{id}" of the last match of the previous page
+fn page_token(record: &MatchRecord) -> String {
+    format!("{}:{}", record.matched_at, record.id)
+}
+
+fn parse_page_token(token: &str) -> Result<Option<(u64, String)>, Status> {
+    if token.is_empty() {
+        return Ok(None);
+    }
+    let mut parts = token.splitn(2, ':');
+    match (
+        parts.next().and_then(|at| at.parse::<u64>().ok()),
+        parts.next(),
+    ) {
+        (Some(matched_at), Some(id)) if !id.is_empty() => Ok(Some((matched_at, id.to_string()))),
+        _ => Err(Status::invalid_argument("invalid page_token")),
+    }
+}
+
+fn quota_exhausted(kind: QuotaKind, resets_at: SystemTime, now: SystemTime) -> Status {
+    let reset = resets_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let retry_after = resets_at.duration_since(now).unwrap_or_default().as_secs();
+    let mut metadata = MetadataMap::new();
+    metadata.insert(QUOTA_RESET_METADATA, MetadataValue::from(reset));
+    metadata.insert(RETRY_AFTER_METADATA, MetadataValue::from(retry_after));
+    Status::with_metadata(
+        Code::ResourceExhausted,
+        format!("{} quota exhausted", kind.name()),
+        metadata,
+    )
+}
+
+// What the caller is looking for
+#[derive(Debug, PartialEq)]
+struct QueueFilters {
+    radius: u32,
+    min_age: i32,
+    max_age: i32,
+    genders: Vec<i32>,
+}
+
+// From the request, or else the caller's stored preferences
+fn queue_filters(request: &GetQueueRequest, stored: &Preferences) -> Result<QueueFilters, Status> {
+    let radius = match (request.radius, stored.max_distance) {
+        (0, 0) => DEFAULT_QUEUE_RADIUS,
+        (0, stored) => stored,
+        (radius, _) => radius,
+    };
+    let (min_age, max_age) = match request.age_range.as_slice() {
+        [] => (
+            if stored.min_age == 0 {
+                MIN_AGE
+            } else {
+                stored.min_age
+            },
+            if stored.max_age == 0 {
+                MAX_AGE
+            } else {
+                stored.max_age
+            },
+        ),
+        [min, max] => (*min, *max),
+        _ => return Err(Status::invalid_argument("age_range must be [min, max]")),
+    };
+    // The single deprecated gender still counts when it's set
+    let genders = if !request.genders.is_empty() {
+        request.genders.clone()
+    } else if request.gender != Gender::Unspecified as i32 {
+        vec![request.gender]
+    } else {
+        stored.genders.clone()
+    };
+    check_genders(&genders)?;
+    Ok(QueueFilters {
+        radius,
+        min_age,
+        max_age,
+        genders,
+    })
+}
+
+// Genders someone can be looking for, Unspecified isn't one
+fn check_genders(genders: &[i32]) -> Result<(), Status> {
+    if genders.iter().any(|gender| {
+        Gender::from_i32(*gender)
+            .filter(|gender| *gender != Gender::Unspecified)
+            .is_none()
+    }) {
+        return Err(Status::invalid_argument("unknown gender"));
+    }
+    Ok(())
+}
+
+// Checks preferences before they're stored, sorting out duplicate genders
+fn normalize_preferences(mut preferences: Preferences) -> Result<Preferences, Status> {
+    check_genders(&preferences.genders)?;
+    preferences.genders.sort();
+    preferences.genders.dedup();
+    let valid_age = |age: i32| age == 0 || (MIN_AGE..=MAX_AGE).contains(&age);
+    if !valid_age(preferences.min_age) || !valid_age(preferences.max_age) {
+        return Err(Status::invalid_argument(format!(
+            "ages must be between {} and {}",
+            MIN_AGE, MAX_AGE
+        )));
+    }
+    if preferences.min_age != 0
+        && preferences.max_age != 0
+        && preferences.min_age > preferences.max_age
+    {
+        return Err(Status::invalid_argument("min_age can't be above max_age"));
+    }
+    if preferences.max_distance > MAX_PREFERRED_DISTANCE {
+        return Err(Status::invalid_argument(format!(
+            "max_distance can't be above {} miles",
+            MAX_PREFERRED_DISTANCE
+        )));
+    }
+    Ok(preferences)
+}
+
+// Right and SuperLike both count towards a match
+fn likes(swipe: i32) -> bool {
+    swipe == Swipe::Right as i32 || swipe == Swipe::SuperLike as i32
+}
+
+// The quota a swipe uses, left swipes are free
+fn swipe_quota(swipe: i32) -> Option<QuotaKind> {
+    match Swipe::from_i32(swipe) {
+        Some(Swipe::Right) => Some(QuotaKind::RightSwipes),
+        Some(Swipe::SuperLike) => Some(QuotaKind::SuperLikes),
+        _ => None,
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
 }
 
+// When the user swiped, rejecting clocks too far ahead of the server's
+fn swipe_time(client_time: Option<Timestamp>) -> Result<SystemTime, Status> {
+    let now = SystemTime::now();
+    let time = match client_time {
+        Some(time) if time.seconds >= 0 && (0..1_000_000_000).contains(&time.nanos) => {
+            UNIX_EPOCH + Duration::new(time.seconds as u64, time.nanos as u32)
+        }
+        Some(_) => return Err(Status::invalid_argument("client_time is out of range")),
+        None => return Ok(now),
+    };
+    if time > now + MAX_CLIENT_SKEW {
+        return Err(Status::invalid_argument("client_time is in the future"));
+    }
+    Ok(time)
+}
+
+// What an idempotency key is bound to, a retry must send the same swipe
+fn swipe_fingerprint(request: &SwipeV2Request) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request.swipee_uid.hash(&mut hasher);
+    request.swipe.hash(&mut hasher);
+    request
+        .client_time
+        .as_ref()
+        .map(|time| (time.seconds, time.nanos))
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+fn internal(err: elasticsearch::Error) -> Status {
+    error!("Elastic err: {}", err);
+    Status::internal("internal server error")
+}
+
+// Yields the users in the order elastic ranked them
 pub struct UserStream {
-    users: Vec<User>,
+    users: std::vec::IntoIter<User>,
 }
 
 impl Stream for UserStream {
     type Item = Result<User, Status>;
 
     fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.users.pop() {
+        match self.users.next() {
             Some(user) => Poll::Ready(Some(Ok(user))),
             None => Poll::Ready(None),
         }
@@ -62,46 +642,621 @@ impl RecommendationService for MainRecommendactionService {
         &self,
         request: Request<GetQueueRequest>,
     ) -> Result<Response<Self::GetQueueStream>, Status> {
+        let uid = self.caller_uid(&request, &request.get_ref().uid).await?;
+        self.take_quota(&uid, QuotaKind::QueueRequests).await?;
         let request = request.into_inner();
+        // Candidates are only shown when their preferences accept the caller
+        let (_, requester) = self
+            .elastic_operator
+            .find_user(&uid)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found("user not found"))?;
+        let filters = queue_filters(&request, &requester.preferences.clone().unwrap_or_default())?;
         let user_shards = self.searcher.get_shards_from_radius(
             request.longitude,
             request.latitude,
-            request.radius,
+            filters.radius,
         );
         let es_index: Vec<&str> = user_shards.into_iter().map(|x| x.name.as_str()).collect();
         info!(
             "User {} queue query will hit {} shards",
-            request.uid,
+            uid,
             es_index.len()
         );
-        let users = self
-            .elastic_operator
-            .get_users(
-                es_index,
-                request.latitude,
-                request.longitude,
-                request.radius,
-                request.age_range,
-                request.gender as u64,
-            )
-            .await;
-
-        let user_index = self
-            .searcher
-            .get_shard_from_lng_lat(request.longitude, request.latitude);
-        let potential_matches = self
+        let exclude = vec![uid.clone()];
+        // Hidden both ways, whoever blocked whom
+        let blocked: Vec<String> = self
+            .elastic_operator
+            .blocks_involving(&uid)
+            .await
+            .map_err(internal)?
+            .iter()
+            .map(|block| block.other(&uid).to_string())
+            .collect();
+        let super_likers = self
             .elastic_operator
-            .get_user(&user_index.name.as_str(), request.uid);
+            .swipers_of(&uid, Swipe::SuperLike as i32, BOOSTED_QUEUE_SIZE)
+            .await
+            .map_err(internal)?;
+        let mut queue = QueueQuery {
+            lat: request.latitude,
+            lon: request.longitude,
+            distance: filters.radius,
+            min_age: filters.min_age,
+            max_age: filters.max_age,
+            genders: &filters.genders,
+            requester_age: requester.age,
+            requester_gender: requester.gender,
+            exclude: &exclude,
+            blocked: &blocked,
+            boost: &super_likers,
+            from: 0,
+        };
+        // Users already swiped on don't come back. They're dropped from each
+        // page of candidates rather than excluded up front, which would mean
+        // sending every swipe the caller ever made along with the search.
+        let mut users: Vec<User> = vec![];
+        while users.len() < QUEUE_SIZE && queue.from < MAX_QUEUE_CANDIDATES {
+            let candidates = self
+                .elastic_operator
+                .get_users(es_index.clone(), &queue)
+                .await
+                .map_err(internal)?;
+            let uids: Vec<String> = candidates.iter().map(|user| user.uid.clone()).collect();
+            let swiped = self
+                .elastic_operator
+                .swiped_among(&uid, &uids)
+                .await
+                .map_err(internal)?;
+            users.extend(
+                candidates
+                    .into_iter()
+                    .filter(|user| !swiped.contains(&user.uid)),
+            );
+            if uids.len() < QUEUE_PAGE_SIZE {
+                break;
+            }
+            queue.from += QUEUE_PAGE_SIZE;
+        }
+        users.truncate(QUEUE_SIZE);
 
-        let user_stream = UserStream { users };
+        let user_stream = UserStream {
+            users: users.into_iter(),
+        };
 
         Ok(Response::new(user_stream))
     }
 
-    async fn swipe(
+    async fn swipe(&self, swipe: Request<SwipeRequest>) -> Result<Response<SwipeResponse>, Status> {
+        let claimed = swipe
+            .get_ref()
+            .swiper
+            .as_ref()
+            .map(|swiper| swiper.uid.clone())
+            .unwrap_or_default();
+        let uid = self.caller_uid(&swipe, &claimed).await?;
+        let swipe = swipe.into_inner();
+        warn!("User {} called the deprecated Swipe, use SwipeV2", uid);
+        let swipee_uid = swipe.swipee.map(|swipee| swipee.uid).unwrap_or_default();
+        let direction = Swipe::from_i32(swipe.swipe)
+            .ok_or_else(|| Status::invalid_argument("unknown swipe"))?;
+        let response = self
+            .record_swipe(&uid, &swipee_uid, direction, SystemTime::now())
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn swipe_v2(
         &self,
-        _swipe: Request<SwipeRequest>,
+        request: Request<SwipeV2Request>,
     ) -> Result<Response<SwipeResponse>, Status> {
-        Err(Status::unimplemented("Not Implemented"))
+        let uid = self.caller(&request).await?;
+        let request = request.into_inner();
+        let key = &request.idempotency_key;
+        // Held until the swipe is recorded, released if it fails
+        let reservation = if key.is_empty() {
+            None
+        } else {
+            let fingerprint = swipe_fingerprint(&request);
+            match self.swipe_keys.reserve(&uid, key, fingerprint) {
+                Reserved::New(reservation) => Some(reservation),
+                Reserved::Done(response) => {
+                    info!("Replaying swipe of user {} for key {}", uid, key);
+                    return Ok(Response::new(response));
+                }
+                Reserved::InProgress => {
+                    return Err(Status::aborted(
+                        "a swipe with this idempotency_key is in progress",
+                    ))
+                }
+                Reserved::Mismatch => {
+                    return Err(Status::invalid_argument(
+                        "idempotency_key was used for another swipe",
+                    ))
+                }
+            }
+        };
+        let direction = Swipe::from_i32(request.swipe)
+            .ok_or_else(|| Status::invalid_argument("unknown swipe"))?;
+        let swiped_at = swipe_time(request.client_time.clone())?;
+
+        let response = self
+            .record_swipe(&uid, &request.swipee_uid, direction, swiped_at)
+            .await?;
+        if let Some(reservation) = reservation {
+            reservation.complete(response.clone());
+        }
+        Ok(Response::new(response))
+    }
+
+    async fn undo_last_swipe(
+        &self,
+        request: Request<UndoLastSwipeRequest>,
+    ) -> Result<Response<UndoLastSwipeResponse>, Status> {
+        let uid = self.caller(&request).await?;
+        let last = self
+            .elastic_operator
+            .last_swipe(&uid)
+            .await
+            .map_err(internal)?;
+        let window = self.limits.undo_window.as_millis() as u64;
+        let last = match last {
+            Some(last) if last.recorded_at + window >= millis(SystemTime::now()) => last,
+            _ => return Err(Status::failed_precondition("no recent swipe to undo")),
+        };
+
+        // Back to the decision it replaced, or to never having swiped
+        let restored = last.restored();
+        match &restored {
+            Some(restored) => self.elastic_operator.record_swipe(restored).await,
+            None => self.elastic_operator.delete_swipe(&uid, &last.swipee).await,
+        }
+        .map_err(internal)?;
+        // A match the earlier decision made stays
+        let unmatched = likes(last.swipe)
+            && !restored.map_or(false, |restored| likes(restored.swipe))
+            && self
+                .elastic_operator
+                .delete_match(&uid, &last.swipee)
+                .await
+                .map_err(internal)?;
+        // Counted again in the window the swipe was made in
+        if let Some(kind) = swipe_quota(last.swipe) {
+            let taken_at = UNIX_EPOCH + Duration::from_millis(last.recorded_at);
+            self.give_back_quota(&uid, kind, taken_at).await;
+        }
+        info!(
+            "User {} undid their swipe on {}, unmatched: {}",
+            uid, last.swipee, unmatched
+        );
+        Ok(Response::new(UndoLastSwipeResponse {
+            swipee_uid: last.swipee,
+            swipe: last.swipe,
+            unmatched,
+        }))
+    }
+
+    async fn update_location(
+        &self,
+        request: Request<UpdateLocationRequest>,
+    ) -> Result<Response<UpdateLocationResponse>, Status> {
+        let uid = self.caller_uid(&request, &request.get_ref().uid).await?;
+        let request = request.into_inner();
+        let location = match request.location {
+            Some(location) => location,
+            None => return Err(Status::invalid_argument("please provide location")),
+        };
+        if !(-90.0..=90.0).contains(&location.latitude)
+            || !(-180.0..=180.0).contains(&location.longitude)
+        {
+            return Err(Status::invalid_argument("location is out of range"));
+        }
+
+        match self.move_user(&uid, location).await.map_err(internal)? {
+            Some((shard, moved)) => Ok(Response::new(UpdateLocationResponse { shard, moved })),
+            None => Err(Status::not_found("user not found")),
+        }
+    }
+    async fn export_user_data(
+        &self,
+        request: Request<ExportUserDataRequest>,
+    ) -> Result<Response<ExportUserDataResponse>, Status> {
+        let uid = self.caller_uid(&request, &request.get_ref().uid).await?;
+        let (shard, user) = self
+            .elastic_operator
+            .find_user(&uid)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found("user not found"))?;
+        let swipes = self
+            .elastic_operator
+            .swipes_by(&uid)
+            .await
+            .map_err(internal)?;
+        let matches = self.all_matches(&uid).await.map_err(internal)?;
+        let blocks: Vec<BlockRecord> = self
+            .elastic_operator
+            .blocks_involving(&uid)
+            .await
+            .map_err(internal)?
+            .into_iter()
+            .filter(|block| block.blocker == uid)
+            .collect();
+        let serialize_err = |err: serde_json::Error| {
+            error!("Err serializing user {}: {}", uid, err);
+            Status::internal("internal server error")
+        };
+        Ok(Response::new(ExportUserDataResponse {
+            shard,
+            document: serde_json::to_string(&user).map_err(serialize_err)?,
+            swipes: serde_json::to_string(&swipes).map_err(serialize_err)?,
+            matches: serde_json::to_string(&matches).map_err(serialize_err)?,
+            blocks: serde_json::to_string(&blocks).map_err(serialize_err)?,
+        }))
+    }
+
+    async fn get_preferences(
+        &self,
+        request: Request<GetPreferencesRequest>,
+    ) -> Result<Response<Preferences>, Status> {
+        let uid = self.caller(&request).await?;
+        let (_, user) = self
+            .elastic_operator
+            .find_user(&uid)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found("user not found"))?;
+        Ok(Response::new(user.preferences.unwrap_or_default()))
+    }
+
+    async fn set_preferences(
+        &self,
+        request: Request<SetPreferencesRequest>,
+    ) -> Result<Response<Preferences>, Status> {
+        let uid = self.caller(&request).await?;
+        let preferences = match request.into_inner().preferences {
+            Some(preferences) => normalize_preferences(preferences)?,
+            None => return Err(Status::invalid_argument("please provide preferences")),
+        };
+        let (index, _) = self
+            .elastic_operator
+            .find_user(&uid)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found("user not found"))?;
+        self.elastic_operator
+            .set_preferences(&index, &uid, &preferences)
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(preferences))
+    }
+
+    async fn block_user(
+        &self,
+        request: Request<BlockUserRequest>,
+    ) -> Result<Response<BlockUserResponse>, Status> {
+        let uid = self.caller(&request).await?;
+        self.block(&uid, &request.into_inner().uid).await?;
+        Ok(Response::new(BlockUserResponse {}))
+    }
+
+    async fn report_user(
+        &self,
+        request: Request<ReportUserRequest>,
+    ) -> Result<Response<ReportUserResponse>, Status> {
+        let uid = self.caller(&request).await?;
+        let request = request.into_inner();
+        if request.uid.is_empty() {
+            return Err(Status::invalid_argument("please provide uid"));
+        }
+        if request.uid == uid {
+            return Err(Status::invalid_argument("users can't report themselves"));
+        }
+        if ReportReason::from_i32(request.reason).is_none() {
+            return Err(Status::invalid_argument("unknown reason"));
+        }
+        if request.details.chars().count() > MAX_REPORT_DETAILS_LENGTH {
+            return Err(Status::invalid_argument(format!(
+                "details can't be longer than {} characters",
+                MAX_REPORT_DETAILS_LENGTH
+            )));
+        }
+        if request.block {
+            self.block(&uid, &request.uid).await?;
+        } else if self
+            .elastic_operator
+            .find_user(&request.uid)
+            .await
+            .map_err(internal)?
+            .is_none()
+        {
+            return Err(Status::not_found("user not found"));
+        }
+
+        self.elastic_operator
+            .file_report(&ReportRecord {
+                reporter: uid,
+                reported: request.uid,
+                reason: request.reason,
+                details: request.details,
+                reported_at: millis(SystemTime::now()),
+                status: REPORT_OPEN.to_string(),
+            })
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(ReportUserResponse {}))
+    }
+
+    async fn list_matches(
+        &self,
+        request: Request<ListMatchesRequest>,
+    ) -> Result<Response<ListMatchesResponse>, Status> {
+        let uid = self.caller(&request).await?;
+        let request = request.into_inner();
+        let page_size = match request.page_size {
+            0 => DEFAULT_MATCH_PAGE_SIZE,
+            size => size.min(MAX_MATCH_PAGE_SIZE),
+        } as usize;
+        let after = parse_page_token(&request.page_token)?;
+
+        // One more than asked tells whether there's another page
+        let mut records = self
+            .elastic_operator
+            .matches_of(&uid, page_size + 1, after)
+            .await
+            .map_err(internal)?;
+        let next_page_token = if records.len() > page_size {
+            records.truncate(page_size);
+            records.last().map(page_token).unwrap_or_default()
+        } else {
+            String::new()
+        };
+        Ok(Response::new(ListMatchesResponse {
+            matches: records
+                .iter()
+                .map(|record| to_match(record, &uid))
+                .collect(),
+            next_page_token,
+        }))
+    }
+
+    async fn unmatch(
+        &self,
+        request: Request<UnmatchRequest>,
+    ) -> Result<Response<UnmatchResponse>, Status> {
+        let uid = self.caller(&request).await?;
+        let other = request.into_inner().uid;
+        if other.is_empty() {
+            return Err(Status::invalid_argument("please provide uid"));
+        }
+        if !self
+            .elastic_operator
+            .delete_match(&uid, &other)
+            .await
+            .map_err(internal)?
+        {
+            return Err(Status::not_found("match not found"));
+        }
+        // Left keeps them out of each other's queue without matching again
+        let now = millis(SystemTime::now());
+        self.elastic_operator
+            .record_swipe(&SwipeRecord {
+                swiper: uid.clone(),
+                swipee: other.clone(),
+                swipe: Swipe::Left as i32,
+                swiped_at: now,
+                recorded_at: now,
+                // Undoing it mustn't bring back a like without its match
+                previous: None,
+            })
+            .await
+            .map_err(internal)?;
+        info!("User {} unmatched {}", uid, other);
+        Ok(Response::new(UnmatchResponse {}))
+    }
+
+    type SubscribeMatchesStream = MatchStream;
+
+    async fn subscribe_matches(
+        &self,
+        request: Request<SubscribeMatchesRequest>,
+    ) -> Result<Response<Self::SubscribeMatchesStream>, Status> {
+        let uid = self.caller(&request).await?;
+        info!("User {} subscribed to matches", uid);
+        Ok(Response::new(self.match_subscriptions.subscribe(&uid)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn page_tokens_round_trip() {
+        let record = MatchRecord::new("a", "b", 1234);
+        assert_eq!(
+            parse_page_token(&page_token(&record)).unwrap(),
+            Some((1234, "a_b".to_string()))
+        );
+        assert_eq!(parse_page_token("").unwrap(), None);
+        assert!(parse_page_token("a_b").is_err());
+        assert!(parse_page_token("1234:").is_err());
+    }
+
+    #[test]
+    fn idempotency_keys_are_bound_to_the_swipe() {
+        let request = SwipeV2Request {
+            swipee_uid: "b".to_string(),
+            swipe: Swipe::Right as i32,
+            idempotency_key: "key".to_string(),
+            ..SwipeV2Request::default()
+        };
+        let retry = SwipeV2Request {
+            idempotency_key: "other".to_string(),
+            ..request.clone()
+        };
+        assert_eq!(swipe_fingerprint(&request), swipe_fingerprint(&retry));
+        let left = SwipeV2Request {
+            swipe: Swipe::Left as i32,
+            ..request.clone()
+        };
+        assert_ne!(swipe_fingerprint(&request), swipe_fingerprint(&left));
+        let later = SwipeV2Request {
+            client_time: Some(Timestamp {
+                seconds: 1,
+                nanos: 0,
+            }),
+            ..request.clone()
+        };
+        assert_ne!(swipe_fingerprint(&request), swipe_fingerprint(&later));
+    }
+
+    #[test]
+    fn exhausted_quota_tells_when_it_resets() {
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+        let resets_at = UNIX_EPOCH + Duration::from_secs(120);
+        let status = quota_exhausted(QuotaKind::QueueRequests, resets_at, now);
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(status.metadata().get(QUOTA_RESET_METADATA).unwrap(), "120");
+        assert_eq!(status.metadata().get(RETRY_AFTER_METADATA).unwrap(), "20");
+    }
+
+    fn queue_request(radius: u32, age_range: Vec<i32>) -> GetQueueRequest {
+        GetQueueRequest {
+            radius,
+            age_range,
+            ..GetQueueRequest::default()
+        }
+    }
+
+    #[test]
+    fn queue_falls_back_to_stored_preferences() {
+        let stored = Preferences {
+            genders: vec![Gender::Female as i32],
+            min_age: 25,
+            max_age: 0,
+            max_distance: 10,
+        };
+        assert_eq!(
+            queue_filters(&queue_request(0, vec![]), &stored).unwrap(),
+            QueueFilters {
+                radius: 10,
+                min_age: 25,
+                max_age: MAX_AGE,
+                genders: vec![Gender::Female as i32],
+            }
+        );
+        assert_eq!(
+            queue_filters(&queue_request(30, vec![21, 30]), &stored).unwrap(),
+            QueueFilters {
+                radius: 30,
+                min_age: 21,
+                max_age: 30,
+                genders: vec![Gender::Female as i32],
+            }
+        );
+        assert_eq!(
+            queue_filters(&queue_request(0, vec![]), &Preferences::default()).unwrap(),
+            QueueFilters {
+                radius: DEFAULT_QUEUE_RADIUS,
+                min_age: MIN_AGE,
+                max_age: MAX_AGE,
+                genders: vec![],
+            }
+        );
+        assert!(queue_filters(&queue_request(0, vec![21]), &stored).is_err());
+    }
+
+    #[test]
+    fn queue_genders_from_the_request_first() {
+        let stored = Preferences {
+            genders: vec![Gender::Female as i32],
+            ..Preferences::default()
+        };
+        let request = GetQueueRequest {
+            genders: vec![Gender::NonBinary as i32, Gender::Other as i32],
+            ..GetQueueRequest::default()
+        };
+        assert_eq!(
+            queue_filters(&request, &stored).unwrap().genders,
+            vec![Gender::NonBinary as i32, Gender::Other as i32]
+        );
+        let request = GetQueueRequest {
+            gender: Gender::Male as i32,
+            ..GetQueueRequest::default()
+        };
+        assert_eq!(
+            queue_filters(&request, &stored).unwrap().genders,
+            vec![Gender::Male as i32]
+        );
+        let request = GetQueueRequest {
+            genders: vec![Gender::Unspecified as i32],
+            ..GetQueueRequest::default()
+        };
+        assert!(queue_filters(&request, &stored).is_err());
+    }
+
+    #[test]
+    fn preferences_are_checked() {
+        let preferences = Preferences {
+            genders: vec![
+                Gender::Female as i32,
+                Gender::Male as i32,
+                Gender::Female as i32,
+            ],
+            min_age: 21,
+            max_age: 30,
+            max_distance: 25,
+        };
+        assert_eq!(
+            normalize_preferences(preferences.clone()).unwrap().genders,
+            vec![Gender::Male as i32, Gender::Female as i32]
+        );
+        for invalid in vec![
+            Preferences {
+                genders: vec![42],
+                ..preferences.clone()
+            },
+            Preferences {
+                genders: vec![Gender::Unspecified as i32],
+                ..preferences.clone()
+            },
+            Preferences {
+                min_age: 17,
+                ..preferences.clone()
+            },
+            Preferences {
+                min_age: 31,
+                ..preferences.clone()
+            },
+            Preferences {
+                max_distance: MAX_PREFERRED_DISTANCE + 1,
+                ..preferences.clone()
+            },
+        ] {
+            assert!(normalize_preferences(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn super_likes_count_as_likes() {
+        assert!(likes(Swipe::Right as i32));
+        assert!(likes(Swipe::SuperLike as i32));
+        assert!(!likes(Swipe::Left as i32));
+    }
+
+    #[test]
+    fn match_time_keeps_millis() {
+        let record = MatchRecord::new("a", "b", 1_500);
+        let seen = to_match(&record, "b");
+        assert_eq!(seen.uid, "a");
+        assert_eq!(
+            seen.matched_at,
+            Some(Timestamp {
+                seconds: 1,
+                nanos: 500_000_000
+            })
+        );
     }
 }
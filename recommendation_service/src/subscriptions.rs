@@ -0,0 +1,128 @@
+use super::recommendation::Match;
+use futures::{
+    task::{Context, Poll},
+    Stream,
+};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tonic::Status;
+
+// Each subscription's sender, tagged so its stream can take it out again
+type Subscribers = Mutex<HashMap<String, Vec<(u64, UnboundedSender<Match>)>>>;
+
+/*
+Connected SubscribeMatches callers by uid. Only matches made through this
+instance reach them, a caller connected to another instance finds them
+with ListMatches.
+*/
+#[derive(Default)]
+pub struct MatchSubscriptions {
+    subscribers: Arc<Subscribers>,
+    next_id: AtomicU64,
+}
+
+impl MatchSubscriptions {
+    // A user may be subscribed from several devices at once
+    pub fn subscribe(&self, uid: &str) -> MatchStream {
+        let (sender, receiver) = unbounded_channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers
+            .entry(uid.to_string())
+            .or_insert_with(Vec::new)
+            .push((id, sender));
+        MatchStream {
+            receiver,
+            uid: uid.to_string(),
+            id,
+            subscribers: Arc::downgrade(&self.subscribers),
+        }
+    }
+
+    pub fn notify(&self, uid: &str, new_match: Match) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(senders) = subscribers.get_mut(uid) {
+            senders.retain(|(_, sender)| sender.send(new_match.clone()).is_ok());
+            if senders.is_empty() {
+                subscribers.remove(uid);
+            }
+        }
+    }
+}
+
+// Unsubscribes when dropped, which tonic does once the client goes away
+pub struct MatchStream {
+    receiver: UnboundedReceiver<Match>,
+    uid: String,
+    id: u64,
+    subscribers: Weak<Subscribers>,
+}
+
+impl Drop for MatchStream {
+    fn drop(&mut self) {
+        let subscribers = match self.subscribers.upgrade() {
+            Some(subscribers) => subscribers,
+            None => return,
+        };
+        let mut subscribers = subscribers.lock().unwrap();
+        if let Some(senders) = subscribers.get_mut(&self.uid) {
+            senders.retain(|(id, _)| *id != self.id);
+            if senders.is_empty() {
+                subscribers.remove(&self.uid);
+            }
+        }
+    }
+}
+
+impl Stream for MatchStream {
+    type Item = Result<Match, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx).map(|next| next.map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    fn new_match(uid: &str) -> Match {
+        Match {
+            uid: uid.to_string(),
+            matched_at: None,
+        }
+    }
+
+    #[test]
+    fn notifies_every_subscription_of_the_user() {
+        let subscriptions = MatchSubscriptions::default();
+        let mut phone = subscriptions.subscribe("a");
+        let mut laptop = subscriptions.subscribe("a");
+        let mut other = subscriptions.subscribe("b");
+        subscriptions.notify("a", new_match("c"));
+        drop(subscriptions);
+
+        assert_eq!(block_on(phone.next()).unwrap().unwrap(), new_match("c"));
+        assert_eq!(block_on(laptop.next()).unwrap().unwrap(), new_match("c"));
+        assert!(block_on(other.next()).is_none());
+    }
+
+    #[test]
+    fn forgets_dropped_subscriptions() {
+        let subscriptions = MatchSubscriptions::default();
+        let phone = subscriptions.subscribe("a");
+        let mut laptop = subscriptions.subscribe("a");
+        drop(phone);
+        assert_eq!(subscriptions.subscribers.lock().unwrap()["a"].len(), 1);
+
+        subscriptions.notify("a", new_match("b"));
+        assert_eq!(block_on(laptop.next()).unwrap().unwrap(), new_match("b"));
+        drop(laptop);
+        assert!(subscriptions.subscribers.lock().unwrap().is_empty());
+    }
+}
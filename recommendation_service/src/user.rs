@@ -0,0 +1 @@
+tonic::include_proto!("user_svc");
\ No newline at end of file
@@ -1,4 +1,11 @@
 fn main() -> Result<(),Box<dyn std::error::Error>> {
     tonic_build::compile_protos("proto/user.proto")?;
+    // Only a client, to read what the recommendation service holds on a user
+    tonic_build::configure()
+        .build_server(false)
+        .compile(
+            &["../recommendation_service/proto/recommendation/recommendation.proto"],
+            &["../recommendation_service/proto/recommendation"]
+        )?;
     Ok(())
 }
\ No newline at end of file
@@ -1,267 +1,24 @@
-/*
-NGL lots of big funcs in here...getting logic inplace before breaking up
-*/
 extern crate user_service;
 
-use base64::encode as b64_encode;
-use bcrypt::hash;
 use env_logger;
-use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
-use log::info;
-use rusoto_core::{Region, RusotoError};
-use rusoto_dynamodb::{
-    AttributeDefinition, AttributeValue, CreateTableInput, DescribeTableError, DescribeTableInput,
-    DynamoDb, DynamoDbClient, GetItemError, GetItemInput, KeySchemaElement, ProvisionedThroughput,
-    PutItemError, PutItemInput, Tag,
+use log::{info, warn};
+use rusoto_core::Region;
+use rusoto_dynamodb::DynamoDbClient;
+use std::env;
+use std::fs;
+use tonic::transport::Server;
+use user_service::events::{
+    relay_outbox, EventPublisher, FilePublisher, KafkaPublisher, LogPublisher,
 };
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::SystemTime;
-use tonic::{transport::Server, Request, Response, Status};
-use user_service::user::user_service_server::{UserService, UserServiceServer};
-use user_service::user::{AuthRequest, AuthResponse, NewUserRequest, NewUserResponse, User};
-use uuid::Uuid;
-
-const DEFAULT_COST: u32 = 10;
-const SECRET: &str = "my-secret";
-const DEFAULT_ALGORITHM: Algorithm = Algorithm::HS256;
-const VALID_TIME_SEC: u64 = 8 * 60 * 60; // 8 hours in seconds
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    exp: String,
-    uid: String,
-}
-
-pub struct MainUserService {
-    client: DynamoDbClient,
-}
-
-impl MainUserService {
-    fn new(client: DynamoDbClient) -> Self {
-        Self { client }
-    }
-
-    async fn create_table(&self) {
-        info!("Checking if table exists");
-        match self
-            .client
-            .describe_table(DescribeTableInput {
-                table_name: "date-app-user-service".to_string(),
-            })
-            .await
-        {
-            Ok(_) => {
-                info!("Table exists");
-            }
-            Err(err) => {
-                match err {
-                    RusotoError::Service(err) => {
-                        match err {
-                            DescribeTableError::ResourceNotFound(_) => {
-                                info!("Table does not exist: Creating");
-                                // Create table
-                                let create_table_input = CreateTableInput {
-                                    attribute_definitions: vec![AttributeDefinition {
-                                        attribute_name: "username".to_string(),
-                                        attribute_type: "S".to_string(),
-                                    }],
-                                    billing_mode: None,
-                                    global_secondary_indexes: None,
-                                    key_schema: vec![KeySchemaElement {
-                                        attribute_name: "username".to_string(),
-                                        key_type: "HASH".to_string(),
-                                    }],
-                                    local_secondary_indexes: None,
-                                    provisioned_throughput: Some(ProvisionedThroughput {
-                                        read_capacity_units: 1,
-                                        write_capacity_units: 1,
-                                    }),
-                                    sse_specification: None,
-                                    stream_specification: None,
-                                    table_name: "date-app-user-service".to_string(),
-                                    tags: Some(vec![Tag {
-                                        key: "service".to_string(),
-                                        value: "user-service".to_string(),
-                                    }]),
-                                };
-                                match self.client.create_table(create_table_input).await {
-                                    Ok(_) => (),
-                                    Err(err) => panic!("error creating table: {}", err),
-                                };
-                            }
-                            _ => panic!("error creating table: {}", err),
-                        }
-                    }
-                    _ => panic!("error creating table: {}", err),
-                }
-            }
-        }
-    }
-
-    async fn create_item_from_user(&self, user: User) -> Result<(), RusotoError<PutItemError>> {
-        let mut put_item = PutItemInput::default();
-        put_item.table_name = "date-app-user-service".to_string();
-        put_item.condition_expression = Some(String::from("attribute_not_exists(username)"));
-        let mut username_attr = AttributeValue::default();
-        username_attr.s = Some(user.username);
-        put_item.item.insert("username".to_string(), username_attr);
-
-        let mut password_attr = AttributeValue::default();
-        password_attr.s = Some(user.password);
-        put_item.item.insert("password".to_string(), password_attr);
-
-        let mut uid_attr = AttributeValue::default();
-        uid_attr.s = Some(user.uid);
-        put_item.item.insert("uid".to_string(), uid_attr);
-
-        // Location
-        let mut location_attr = AttributeValue::default();
-        let mut hash_location = HashMap::new();
-        let location = user.location.unwrap();
-
-        let mut lat_attr = AttributeValue::default();
-        lat_attr.n = Some(location.latitude.to_string());
-        hash_location.insert("latitude".to_string(), lat_attr);
-
-        let mut lng_attr = AttributeValue::default();
-        lng_attr.n = Some(location.longitude.to_string());
-        hash_location.insert("longitude".to_string(), lng_attr);
-
-        location_attr.m = Some(hash_location);
-        put_item.item.insert("location".to_string(), location_attr);
-
-        return match self.client.put_item(put_item).await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
-        };
-    }
-
-    fn create_jwt_token(uid: String) -> String {
-        let key = EncodingKey::from_base64_secret(b64_encode(SECRET).as_str()).unwrap();
-        let header = Header::new(DEFAULT_ALGORITHM);
-        let exp = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-            Ok(n) => {
-                let current_epoch = n.as_secs();
-                format!("{}", current_epoch + VALID_TIME_SEC)
-            }
-            Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-        };
-
-        let claims = Claims { exp: exp, uid: uid };
-        encode(&header, &claims, &key).unwrap()
-    }
-
-    async fn get_user(
-        &self,
-        username: String,
-    ) -> Result<(String, String, String, (f64, f64)), RusotoError<GetItemError>> {
-        let mut get_item = GetItemInput::default();
-        get_item.consistent_read = Some(true);
-        get_item.table_name = "date-app-user-service".to_string();
-
-        let mut username_attr = AttributeValue::default();
-        username_attr.s = Some(username);
-        get_item.key.insert("username".to_string(), username_attr);
-
-        return match self.client.get_item(get_item).await {
-            Ok(item) => {
-                let mut user = item.item.unwrap();
-                let mut location = user.remove("location").unwrap().m.unwrap();
-                Ok((
-                    user.remove("uid").unwrap().s.unwrap(),
-                    user.remove("username").unwrap().s.unwrap(),
-                    user.remove("password").unwrap().s.unwrap(),
-                    (
-                        location
-                            .remove("longitude")
-                            .unwrap()
-                            .n
-                            .unwrap()
-                            .parse::<f64>()
-                            .unwrap(),
-                        location
-                            .remove("latitude")
-                            .unwrap()
-                            .n
-                            .unwrap()
-                            .parse::<f64>()
-                            .unwrap(),
-                    ), // Storing longitude/latitude as its required to find users shard
-                ))
-            }
-            Err(err) => Err(err),
-        };
-    }
-}
-
-#[tonic::async_trait]
-impl UserService for MainUserService {
-    async fn new_user(
-        &self,
-        request: Request<NewUserRequest>,
-    ) -> Result<Response<NewUserResponse>, Status> {
-        let mut user = (request.into_inner() as NewUserRequest).user.unwrap();
-        user.uid = Uuid::new_v4().to_string();
-        user.password = hash(user.password, DEFAULT_COST).unwrap();
-        if user.username.is_empty() || user.password.is_empty() {
-            return Err(Status::unauthenticated(
-                "please provide username and password",
-            ));
-        }
-        return match self.create_item_from_user(user).await {
-            Ok(_) => Ok(Response::new(NewUserResponse {})),
-            Err(err) => match err {
-                RusotoError::Service(err) => match err {
-                    PutItemError::ConditionalCheckFailed(err) => {
-                        info!("conditional err: {}", err);
-                        Err(Status::already_exists("user already exists"))
-                    }
-                    _ => {
-                        info!("Err creating user: {}", err);
-                        return Err(Status::internal("internal server error"));
-                    }
-                },
-                _ => {
-                    info!("Err creating user: {}", err);
-                    return Err(Status::internal("internal server error"));
-                }
-            },
-        };
-
-        // Produce message to Kafka saying new user has been added
-    }
-
-    async fn auth(&self, request: Request<AuthRequest>) -> Result<Response<AuthResponse>, Status> {
-        let auth = request.into_inner();
-        info!("Auth: Username: {} Password: ****", auth.username);
-
-        if auth.username.is_empty() || auth.password.is_empty() {
-            return Err(Status::permission_denied(
-                "Please provide username or password",
-            ));
-        }
-
-        let user = match self.get_user(auth.username).await {
-            Ok(user) => user,
-            Err(err) => {
-                info!("Err getting user: {}", err);
-                return Err(Status::internal("internal server error"));
-            }
-        };
-
-        let hashed_password = user.2;
-        let provided_password = auth.password;
-
-        if !bcrypt::verify(provided_password.as_str(), hashed_password.as_str()).unwrap() {
-            return Err(Status::permission_denied("Bad Username or password"));
-        }
-        info!("Auth: Username: {} Successful", user.1);
-        Ok(Response::new(AuthResponse {
-            jwt: MainUserService::create_jwt_token(user.0),
-        }))
-    }
-}
+use user_service::hasher::hasher_from_env;
+use user_service::lockout::Lockout;
+use user_service::notify::{FileNotifier, LogNotifier, Notifier};
+use user_service::password::PasswordPolicy;
+use user_service::recommendation::recommendation_service_client::RecommendationServiceClient;
+use user_service::service::MainUserService;
+use user_service::store::UserStore;
+use user_service::token::{TokenConfig, TokenIssuer};
+use user_service::user::user_service_server::UserServiceServer;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -274,9 +31,68 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         endpoint: "http://dynamodb-local:8000".to_owned(),
     };
 
+    let token_config = TokenConfig::from_env();
+    info!(
+        "Issuing tokens as {} for {} valid for {}s",
+        token_config.issuer,
+        token_config.audience,
+        token_config.lifetime.as_secs()
+    );
+    let tokens = match env::var("JWT_PRIVATE_KEY") {
+        Ok(path) => {
+            info!("Loading JWT signing key from {}", path);
+            TokenIssuer::from_rsa_pem(&fs::read(path)?, token_config)?
+        }
+        Err(_) => {
+            warn!("JWT_PRIVATE_KEY not set: generating an ephemeral signing key");
+            TokenIssuer::generate(token_config)?
+        }
+    };
+
     let client = DynamoDbClient::new(region);
-    let user_service = MainUserService::new(client);
-    user_service.create_table().await;
+    let store = UserStore::new(client.clone());
+    store.create_tables().await;
+    let notifier: Box<dyn Notifier> = match env::var("PASSWORD_RESET_FILE") {
+        Ok(path) => {
+            info!("Writing password reset tokens to {}", path);
+            Box::new(FileNotifier::new(path))
+        }
+        Err(_) => Box::new(LogNotifier),
+    };
+
+    let publisher: Box<dyn EventPublisher> = match env::var("EVENTS_KAFKA_BROKERS") {
+        Ok(brokers) => {
+            let topic = env::var("EVENTS_TOPIC").unwrap_or_else(|_| "user-events".to_string());
+            info!("Publishing user events to {} on {}", topic, brokers);
+            let brokers = brokers.split(',').map(|host| host.trim().to_string());
+            Box::new(KafkaPublisher::new(brokers.collect(), topic)?)
+        }
+        Err(_) => match env::var("EVENTS_FILE") {
+            Ok(path) => {
+                info!("Writing user events to {}", path);
+                Box::new(FilePublisher::new(path))
+            }
+            Err(_) => Box::new(LogPublisher),
+        },
+    };
+    // Events reach the publisher through the outbox, never from the handlers
+    tokio::spawn(relay_outbox(UserStore::new(client), publisher));
+
+    let mut user_service = MainUserService::new(store, tokens)
+        .with_lockout(Lockout::from_env())
+        .with_notifier(notifier)
+        .with_password_policy(PasswordPolicy::from_env())
+        .with_password_hasher(hasher_from_env());
+    match env::var("RECOMMENDATION_SERVICE_URL") {
+        Ok(url) => match RecommendationServiceClient::connect(url.clone()).await {
+            Ok(client) => {
+                info!("Exporting recommendation data from {}", url);
+                user_service = user_service.with_recommendations(client);
+            }
+            Err(err) => warn!("Recommendation service at {} unreachable: {}", url, err),
+        },
+        Err(_) => warn!("RECOMMENDATION_SERVICE_URL not set: exports skip recommendation data"),
+    }
 
     info!("Server listening on {}", addr);
 
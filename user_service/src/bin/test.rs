@@ -6,12 +6,36 @@ Want to look into frameworks, but for now this is good enough
 
 extern crate user_service;
 
+use prost::Message;
+use prost_types::FieldMask;
 use std::{thread, time};
+use user_service::totp;
+use user_service::token::now;
 use user_service::user::user_service_client::UserServiceClient;
 use user_service::user::{
+    BadRequest,
     NewUserRequest, 
     AuthRequest, 
-    
+    ValidateTokenRequest,
+    GetJwksRequest,
+    TokenStatus,
+    AuthResponse,
+    RefreshTokenRequest,
+    ListSessionsRequest,
+    RevokeSessionRequest,
+    LogoutRequest,
+    UnlockAccountRequest,
+    ChangePasswordRequest,
+    RequestPasswordResetRequest,
+    ResetPasswordRequest,
+    EnrollTotpRequest,
+    ConfirmTotpRequest,
+    VerifySecondFactorRequest,
+    GetUserRequest,
+    UpdateUserRequest,
+    DeleteUserRequest,
+    DeleteAccountRequest,
+    ExportMyDataRequest,
     User,
     Location,
     Gender,
@@ -35,6 +59,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     integration_tests::new_user::test_new_user_ok(&mut client).await;
     integration_tests::new_user::test_new_user_already_exists(&mut client).await;
+    integration_tests::new_user::test_new_user_weak_password(&mut client).await;
+    integration_tests::new_user::test_new_user_password_contains_username(&mut client).await;
+
+    integration_tests::token::test_validate_token_ok(&mut client).await;
+    integration_tests::token::test_validate_token_bad_signature(&mut client).await;
+    integration_tests::token::test_validate_token_malformed(&mut client).await;
+    integration_tests::token::test_get_jwks(&mut client).await;
+
+    integration_tests::session::test_refresh_token_rotates(&mut client).await;
+    integration_tests::session::test_refresh_token_reuse_revokes_session(&mut client).await;
+    integration_tests::session::test_list_sessions(&mut client).await;
+    integration_tests::session::test_list_sessions_unauthenticated(&mut client).await;
+    integration_tests::session::test_revoke_session(&mut client).await;
+    integration_tests::session::test_logout_revokes_token(&mut client).await;
+    integration_tests::session::test_logout_all_devices(&mut client).await;
+
+    integration_tests::lockout::test_auth_locks_out_after_failures(&mut client).await;
+    integration_tests::lockout::test_unlock_requires_admin(&mut client).await;
+
+    integration_tests::password::test_change_password(&mut client).await;
+    integration_tests::password::test_request_password_reset_unknown_user(&mut client).await;
+    integration_tests::password::test_reset_password_bad_token(&mut client).await;
+
+    integration_tests::totp::test_totp_login(&mut client).await;
+
+    integration_tests::profile::test_get_user(&mut client).await;
+    integration_tests::profile::test_update_user(&mut client).await;
+    integration_tests::profile::test_update_user_stale_version(&mut client).await;
+    integration_tests::profile::test_update_user_location(&mut client).await;
+    integration_tests::profile::test_update_user_bad_mask(&mut client).await;
+    integration_tests::profile::test_other_users_profile(&mut client).await;
+    integration_tests::profile::test_delete_user(&mut client).await;
+    integration_tests::profile::test_delete_account(&mut client).await;
+    integration_tests::profile::test_export_my_data(&mut client).await;
 
     Ok(())
 }
@@ -42,6 +100,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 mod integration_tests {
     use super::*;
 
+    pub async fn login(client: &mut UserServiceClient<tonic::transport::Channel>, device: &str) -> AuthResponse {
+        let auth_request = tonic::Request::new(
+            AuthRequest{
+                username: "test".to_string(),
+                password: "Swordfish-42".to_string(),
+                device: device.to_string(),
+            }
+        );
+        client.auth(auth_request).await.unwrap().into_inner()
+    }
+
+    pub fn with_bearer<T>(message: T, jwt: &str) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", jwt).parse().unwrap(),
+        );
+        request
+    }
+
     pub async fn setup(client: &mut UserServiceClient<tonic::transport::Channel>) {
         let new_request = tonic::Request::new(
             NewUserRequest {
@@ -49,10 +127,11 @@ mod integration_tests {
                     first_name: "Test".to_string(),
                     last_name: "Test".to_string(),
                     username: "test".to_string(),
-                    password: "test".to_string(),
+                    password: "Swordfish-42".to_string(),
                     uid: "".to_string(),
                     age: 22,
                     gender: Gender::Male as i32,
+                    bio: "".to_string(),
                     location: Some(Location {
                         latitude: 47.606209,
                         longitude: -122.332069,
@@ -73,10 +152,11 @@ mod integration_tests {
                         first_name: "Stephen".to_string(),
                         last_name: "Leyva".to_string(),
                         username: "sleyva".to_string(),
-                        password: "test".to_string(),
+                        password: "Swordfish-42".to_string(),
                         uid: "".to_string(),
                         age: 22,
                         gender: Gender::Male as i32,
+                        bio: "".to_string(),
                         location: Some(Location {
                             latitude: 47.606209,
                             longitude: -122.332069,
@@ -95,10 +175,11 @@ mod integration_tests {
                         first_name: "Test".to_string(),
                         last_name: "Test".to_string(),
                         username: "test".to_string(),
-                        password: "test".to_string(),
+                        password: "Swordfish-42".to_string(),
                         uid: "".to_string(),
                         age: 22,
                         gender: Gender::Male as i32,
+                        bio: "".to_string(),
                         location: Some(Location {
                             latitude: 47.606209,
                             longitude: -122.332069,
@@ -110,6 +191,45 @@ mod integration_tests {
             client.new_user(new_request).await.unwrap_err();
             println!("test_new_user_already_exists: Ok");
         }
+
+        fn weak_user(username: &str, password: &str) -> tonic::Request<NewUserRequest> {
+            tonic::Request::new(
+                NewUserRequest {
+                    user: Some(User {
+                        first_name: "Weak".to_string(),
+                        last_name: "Password".to_string(),
+                        username: username.to_string(),
+                        password: password.to_string(),
+                        uid: "".to_string(),
+                        age: 22,
+                        gender: Gender::Female as i32,
+                        bio: "".to_string(),
+                        location: Some(Location {
+                            latitude: 47.606209,
+                            longitude: -122.332069,
+                        })
+                    })
+                },
+            )
+        }
+
+        pub async fn test_new_user_weak_password(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            for password in &["", "short", "alllowercase", "Password123"] {
+                let status = client.new_user(weak_user("weak", password)).await.unwrap_err();
+                assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+                let details = BadRequest::decode(status.details()).unwrap();
+                assert!(!details.field_violations.is_empty());
+                assert!(details.field_violations.iter().all(|violation| violation.field == "user.password"));
+            }
+            println!("test_new_user_weak_password: Ok");
+        }
+
+        pub async fn test_new_user_password_contains_username(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let status = client.new_user(weak_user("weakling", "My-Weakling-1")).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            println!("test_new_user_password_contains_username: Ok");
+        }
     }
 
     pub mod auth {
@@ -119,7 +239,8 @@ mod integration_tests {
             let auth_request = tonic::Request::new(
                 AuthRequest{
                     username: "test".to_string(),
-                    password: "test".to_string(),
+                    password: "Swordfish-42".to_string(),
+                    device: "".to_string(),
                 }
             );
             client.auth(auth_request).await.unwrap();
@@ -132,6 +253,7 @@ mod integration_tests {
                 AuthRequest{
                     username: "test".to_string(),
                     password: "bad-password".to_string(),
+                    device: "".to_string(),
                 }
             );
     
@@ -144,6 +266,7 @@ mod integration_tests {
                 AuthRequest{
                     username: "".to_string(),
                     password: "bad-password".to_string(),
+                    device: "".to_string(),
                 }
             );
     
@@ -154,6 +277,7 @@ mod integration_tests {
                 AuthRequest{
                     username: "test".to_string(),
                     password: "".to_string(),
+                    device: "".to_string(),
                 }
             );
     
@@ -161,5 +285,708 @@ mod integration_tests {
             println!("test_auth_fails_no_username_password: Ok");
         }
     }
-    
+
+    pub mod token {
+        use super::*;
+
+        pub async fn test_validate_token_ok(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let token = login(client, "token-test").await.jwt;
+            let response = client
+                .validate_token(tonic::Request::new(ValidateTokenRequest { token }))
+                .await
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(response.status, TokenStatus::Valid as i32);
+            let claims = response.claims.unwrap();
+            assert!(!claims.sub.is_empty());
+            assert!(!claims.jti.is_empty());
+            assert!(claims.iat <= claims.nbf && claims.nbf < claims.exp);
+            assert_eq!(claims.roles, vec!["user".to_string()]);
+            println!("test_validate_token_ok: Ok");
+        }
+
+        pub async fn test_validate_token_bad_signature(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let token = login(client, "token-test").await.jwt;
+            let (signed, signature) = token.split_at(token.rfind('.').unwrap() + 1);
+            let mut signature: Vec<char> = signature.chars().collect();
+            signature[0] = if signature[0] == 'A' { 'B' } else { 'A' };
+            let token = format!("{}{}", signed, signature.into_iter().collect::<String>());
+
+            let response = client
+                .validate_token(tonic::Request::new(ValidateTokenRequest { token }))
+                .await
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(response.status, TokenStatus::BadSignature as i32);
+            assert!(response.claims.is_none());
+            println!("test_validate_token_bad_signature: Ok");
+        }
+
+        pub async fn test_validate_token_malformed(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let response = client
+                .validate_token(tonic::Request::new(ValidateTokenRequest { token: "not-a-jwt".to_string() }))
+                .await
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(response.status, TokenStatus::Malformed as i32);
+            println!("test_validate_token_malformed: Ok");
+        }
+
+        pub async fn test_get_jwks(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let keys = client
+                .get_jwks(tonic::Request::new(GetJwksRequest {}))
+                .await
+                .unwrap()
+                .into_inner()
+                .keys;
+
+            assert_eq!(keys.len(), 1);
+            assert_eq!(keys[0].kty, "RSA");
+            assert_eq!(keys[0].alg, "RS256");
+            assert!(!keys[0].n.is_empty() && !keys[0].e.is_empty());
+            println!("test_get_jwks: Ok");
+        }
+    }
+
+    pub mod session {
+        use super::*;
+
+        pub async fn test_refresh_token_rotates(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let first = login(client, "refresh-test").await;
+            let second = client
+                .refresh_token(tonic::Request::new(RefreshTokenRequest { refresh_token: first.refresh_token.clone() }))
+                .await
+                .unwrap()
+                .into_inner();
+
+            assert_eq!(first.session_id, second.session_id);
+            assert_ne!(first.refresh_token, second.refresh_token);
+            assert!(!second.jwt.is_empty());
+
+            client
+                .refresh_token(tonic::Request::new(RefreshTokenRequest { refresh_token: second.refresh_token }))
+                .await
+                .unwrap();
+            println!("test_refresh_token_rotates: Ok");
+        }
+
+        pub async fn test_refresh_token_reuse_revokes_session(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let first = login(client, "reuse-test").await;
+            let second = client
+                .refresh_token(tonic::Request::new(RefreshTokenRequest { refresh_token: first.refresh_token.clone() }))
+                .await
+                .unwrap()
+                .into_inner();
+
+            // Replaying the rotated token kills the whole session
+            let status = client
+                .refresh_token(tonic::Request::new(RefreshTokenRequest { refresh_token: first.refresh_token }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+            client
+                .refresh_token(tonic::Request::new(RefreshTokenRequest { refresh_token: second.refresh_token }))
+                .await
+                .unwrap_err();
+            println!("test_refresh_token_reuse_revokes_session: Ok");
+        }
+
+        pub async fn test_list_sessions(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = login(client, "list-test").await;
+            let sessions = client
+                .list_sessions(with_bearer(ListSessionsRequest {}, &auth.jwt))
+                .await
+                .unwrap()
+                .into_inner()
+                .sessions;
+
+            let current: Vec<_> = sessions.iter().filter(|session| session.current).collect();
+            assert_eq!(current.len(), 1);
+            assert_eq!(current[0].session_id, auth.session_id);
+            assert_eq!(current[0].device, "list-test");
+            println!("test_list_sessions: Ok");
+        }
+
+        pub async fn test_list_sessions_unauthenticated(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let status = client
+                .list_sessions(tonic::Request::new(ListSessionsRequest {}))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+            println!("test_list_sessions_unauthenticated: Ok");
+        }
+
+        pub async fn test_revoke_session(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = login(client, "revoke-test").await;
+            client
+                .revoke_session(with_bearer(RevokeSessionRequest { session_id: auth.session_id.clone() }, &auth.jwt))
+                .await
+                .unwrap();
+
+            client
+                .refresh_token(tonic::Request::new(RefreshTokenRequest { refresh_token: auth.refresh_token }))
+                .await
+                .unwrap_err();
+
+            let status = client
+                .revoke_session(with_bearer(RevokeSessionRequest { session_id: auth.session_id }, &auth.jwt))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound);
+            println!("test_revoke_session: Ok");
+        }
+
+        pub async fn test_logout_revokes_token(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = login(client, "logout-test").await;
+            client
+                .logout(with_bearer(LogoutRequest { all_devices: false }, &auth.jwt))
+                .await
+                .unwrap();
+
+            let response = client
+                .validate_token(tonic::Request::new(ValidateTokenRequest { token: auth.jwt.clone() }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.status, TokenStatus::Revoked as i32);
+
+            client
+                .list_sessions(with_bearer(ListSessionsRequest {}, &auth.jwt))
+                .await
+                .unwrap_err();
+            client
+                .refresh_token(tonic::Request::new(RefreshTokenRequest { refresh_token: auth.refresh_token }))
+                .await
+                .unwrap_err();
+            println!("test_logout_revokes_token: Ok");
+        }
+
+        pub async fn test_logout_all_devices(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let phone = login(client, "phone").await;
+            let laptop = login(client, "laptop").await;
+
+            // Watermark has second precision, tokens from the same second stay valid
+            thread::sleep(time::Duration::from_secs(1));
+            client
+                .logout(with_bearer(LogoutRequest { all_devices: true }, &phone.jwt))
+                .await
+                .unwrap();
+
+            let response = client
+                .validate_token(tonic::Request::new(ValidateTokenRequest { token: laptop.jwt }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.status, TokenStatus::Revoked as i32);
+
+            client
+                .refresh_token(tonic::Request::new(RefreshTokenRequest { refresh_token: laptop.refresh_token }))
+                .await
+                .unwrap_err();
+
+            thread::sleep(time::Duration::from_secs(1));
+            let fresh = login(client, "phone").await;
+            let response = client
+                .validate_token(tonic::Request::new(ValidateTokenRequest { token: fresh.jwt }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.status, TokenStatus::Valid as i32);
+            println!("test_logout_all_devices: Ok");
+        }
+    }
+
+    pub mod lockout {
+        use super::*;
+
+        pub async fn test_auth_locks_out_after_failures(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let new_request = tonic::Request::new(
+                NewUserRequest {
+                    user: Some(User {
+                        first_name: "Locked".to_string(),
+                        last_name: "Out".to_string(),
+                        username: "lockout".to_string(),
+                        password: "Swordfish-42".to_string(),
+                        uid: "".to_string(),
+                        age: 22,
+                        gender: Gender::Female as i32,
+                        bio: "".to_string(),
+                        location: Some(Location {
+                            latitude: 47.606209,
+                            longitude: -122.332069,
+                        })
+                    })
+                },
+            );
+            client.new_user(new_request).await.unwrap();
+
+            // Default policy allows 5 failures before locking the username
+            for _ in 0..6 {
+                let status = client
+                    .auth(tonic::Request::new(AuthRequest {
+                        username: "lockout".to_string(),
+                        password: "bad-password".to_string(),
+                        device: "".to_string(),
+                    }))
+                    .await
+                    .unwrap_err();
+                assert_eq!(status.code(), tonic::Code::PermissionDenied);
+            }
+
+            // Even the right password is refused while locked
+            let status = client
+                .auth(tonic::Request::new(AuthRequest {
+                    username: "lockout".to_string(),
+                    password: "Swordfish-42".to_string(),
+                    device: "".to_string(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+            let retry_after: u64 = status.metadata().get("retry-after").unwrap().to_str().unwrap().parse().unwrap();
+            assert!(retry_after > 0);
+            println!("test_auth_locks_out_after_failures: Ok");
+        }
+
+        pub async fn test_unlock_requires_admin(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = login(client, "unlock-test").await;
+            let status = client
+                .unlock_account(with_bearer(UnlockAccountRequest { username: "lockout".to_string(), address: "".to_string() }, &auth.jwt))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+            println!("test_unlock_requires_admin: Ok");
+        }
+    }
+
+    pub mod password {
+        use super::*;
+
+        pub async fn test_change_password(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let new_request = tonic::Request::new(
+                NewUserRequest {
+                    user: Some(User {
+                        first_name: "Change".to_string(),
+                        last_name: "Password".to_string(),
+                        username: "changepw".to_string(),
+                        password: "old-password".to_string(),
+                        uid: "".to_string(),
+                        age: 22,
+                        gender: Gender::Male as i32,
+                        bio: "".to_string(),
+                        location: Some(Location {
+                            latitude: 47.606209,
+                            longitude: -122.332069,
+                        })
+                    })
+                },
+            );
+            client.new_user(new_request).await.unwrap();
+
+            let auth = client
+                .auth(tonic::Request::new(AuthRequest {
+                    username: "changepw".to_string(),
+                    password: "old-password".to_string(),
+                    device: "".to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+
+            let status = client
+                .change_password(with_bearer(ChangePasswordRequest {
+                    old_password: "wrong-password".to_string(),
+                    new_password: "new-password".to_string(),
+                }, &auth.jwt))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+            // Watermark has second precision, tokens from the same second stay valid
+            thread::sleep(time::Duration::from_secs(1));
+            client
+                .change_password(with_bearer(ChangePasswordRequest {
+                    old_password: "old-password".to_string(),
+                    new_password: "new-password".to_string(),
+                }, &auth.jwt))
+                .await
+                .unwrap();
+
+            let response = client
+                .validate_token(tonic::Request::new(ValidateTokenRequest { token: auth.jwt }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.status, TokenStatus::Revoked as i32);
+
+            client
+                .auth(tonic::Request::new(AuthRequest {
+                    username: "changepw".to_string(),
+                    password: "new-password".to_string(),
+                    device: "".to_string(),
+                }))
+                .await
+                .unwrap();
+            println!("test_change_password: Ok");
+        }
+
+        pub async fn test_request_password_reset_unknown_user(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            client
+                .request_password_reset(tonic::Request::new(RequestPasswordResetRequest { username: "nobody".to_string() }))
+                .await
+                .unwrap();
+            println!("test_request_password_reset_unknown_user: Ok");
+        }
+
+        pub async fn test_reset_password_bad_token(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let status = client
+                .reset_password(tonic::Request::new(ResetPasswordRequest {
+                    token: "not-a-reset-token".to_string(),
+                    new_password: "new-password".to_string(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+            println!("test_reset_password_bad_token: Ok");
+        }
+    }
+
+    pub mod totp {
+        use super::*;
+
+        pub async fn test_totp_login(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let new_request = tonic::Request::new(
+                NewUserRequest {
+                    user: Some(User {
+                        first_name: "Two".to_string(),
+                        last_name: "Factor".to_string(),
+                        username: "totp".to_string(),
+                        password: "Swordfish-42".to_string(),
+                        uid: "".to_string(),
+                        age: 22,
+                        gender: Gender::Female as i32,
+                        bio: "".to_string(),
+                        location: Some(Location {
+                            latitude: 47.606209,
+                            longitude: -122.332069,
+                        })
+                    })
+                },
+            );
+            client.new_user(new_request).await.unwrap();
+
+            let auth_request = AuthRequest {
+                username: "totp".to_string(),
+                password: "Swordfish-42".to_string(),
+                device: "phone".to_string(),
+            };
+            let auth = client.auth(tonic::Request::new(auth_request.clone())).await.unwrap().into_inner();
+            assert!(!auth.second_factor_required);
+
+            let enrollment = client
+                .enroll_totp(with_bearer(EnrollTotpRequest {}, &auth.jwt))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(enrollment.otpauth_uri.starts_with("otpauth://totp/"));
+
+            let code = totp::code(&enrollment.secret, now()).unwrap();
+            let recovery_codes = client
+                .confirm_totp(with_bearer(ConfirmTotpRequest { code }, &auth.jwt))
+                .await
+                .unwrap()
+                .into_inner()
+                .recovery_codes;
+            assert!(!recovery_codes.is_empty());
+
+            let challenge = client.auth(tonic::Request::new(auth_request)).await.unwrap().into_inner();
+            assert!(challenge.second_factor_required);
+            assert!(challenge.jwt.is_empty());
+
+            // A challenge is not an access token
+            let response = client
+                .validate_token(tonic::Request::new(ValidateTokenRequest { token: challenge.challenge_token.clone() }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.status, TokenStatus::WrongAudience as i32);
+
+            let status = client
+                .verify_second_factor(tonic::Request::new(VerifySecondFactorRequest {
+                    challenge_token: challenge.challenge_token.clone(),
+                    code: "not-a-code".to_string(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+            // The TOTP code of this step was spent confirming, use a recovery code
+            let tokens = client
+                .verify_second_factor(tonic::Request::new(VerifySecondFactorRequest {
+                    challenge_token: challenge.challenge_token.clone(),
+                    code: recovery_codes[0].clone(),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(!tokens.jwt.is_empty());
+
+            // Both the challenge and the recovery code are single use
+            client
+                .verify_second_factor(tonic::Request::new(VerifySecondFactorRequest {
+                    challenge_token: challenge.challenge_token,
+                    code: recovery_codes[1].clone(),
+                }))
+                .await
+                .unwrap_err();
+            println!("test_totp_login: Ok");
+        }
+    }
+
+    pub mod profile {
+        use super::*;
+
+        async fn new_user_login(client: &mut UserServiceClient<tonic::transport::Channel>, username: &str) -> AuthResponse {
+            let new_request = tonic::Request::new(
+                NewUserRequest {
+                    user: Some(User {
+                        first_name: "Profile".to_string(),
+                        last_name: "Test".to_string(),
+                        username: username.to_string(),
+                        password: "Swordfish-42".to_string(),
+                        uid: "".to_string(),
+                        age: 30,
+                        gender: Gender::Female as i32,
+                        bio: "hello".to_string(),
+                        location: Some(Location {
+                            latitude: 47.606209,
+                            longitude: -122.332069,
+                        })
+                    })
+                },
+            );
+            client.new_user(new_request).await.unwrap();
+            client
+                .auth(tonic::Request::new(AuthRequest {
+                    username: username.to_string(),
+                    password: "Swordfish-42".to_string(),
+                    device: "".to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+        }
+
+        fn first_name_update(first_name: &str, version: u64, jwt: &str) -> tonic::Request<UpdateUserRequest> {
+            with_bearer(UpdateUserRequest {
+                user: Some(User {
+                    first_name: first_name.to_string(),
+                    ..Default::default()
+                }),
+                update_mask: Some(FieldMask { paths: vec!["first_name".to_string()] }),
+                version,
+            }, jwt)
+        }
+
+        pub async fn test_get_user(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = new_user_login(client, "profile-get").await;
+            let response = client
+                .get_user(with_bearer(GetUserRequest { uid: "".to_string() }, &auth.jwt))
+                .await
+                .unwrap()
+                .into_inner();
+            let user = response.user.unwrap();
+            assert_eq!(user.username, "profile-get");
+            assert_eq!(user.first_name, "Profile");
+            assert_eq!(user.age, 30);
+            assert_eq!(user.gender, Gender::Female as i32);
+            assert_eq!(user.bio, "hello");
+            assert!(user.password.is_empty());
+            assert!(user.location.is_some());
+            assert_eq!(response.version, 1);
+            println!("test_get_user: Ok");
+        }
+
+        pub async fn test_update_user(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = new_user_login(client, "profile-update").await;
+            let response = client
+                .update_user(first_name_update("Updated", 1, &auth.jwt))
+                .await
+                .unwrap()
+                .into_inner();
+            let user = response.user.unwrap();
+            assert_eq!(user.first_name, "Updated");
+            // Fields outside the mask are untouched
+            assert_eq!(user.last_name, "Test");
+            assert_eq!(user.bio, "hello");
+            assert_eq!(response.version, 2);
+            println!("test_update_user: Ok");
+        }
+
+        pub async fn test_update_user_stale_version(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = new_user_login(client, "profile-stale").await;
+            client.update_user(first_name_update("First", 1, &auth.jwt)).await.unwrap();
+            let status = client
+                .update_user(first_name_update("Second", 1, &auth.jwt))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Aborted);
+            println!("test_update_user_stale_version: Ok");
+        }
+
+        pub async fn test_update_user_location(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = new_user_login(client, "profile-location").await;
+            let location_update = |location: Location, version: u64| with_bearer(UpdateUserRequest {
+                user: Some(User {
+                    location: Some(location),
+                    ..Default::default()
+                }),
+                update_mask: Some(FieldMask { paths: vec!["location".to_string()] }),
+                version,
+            }, &auth.jwt);
+
+            let response = client
+                .update_user(location_update(Location { latitude: 40.712776, longitude: -74.005974 }, 1))
+                .await
+                .unwrap()
+                .into_inner();
+            let location = response.user.unwrap().location.unwrap();
+            assert_eq!(location.latitude, 40.712776);
+            assert_eq!(location.longitude, -74.005974);
+
+            let status = client
+                .update_user(location_update(Location { latitude: 91.0, longitude: 0.0 }, 2))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            println!("test_update_user_location: Ok");
+        }
+
+        pub async fn test_update_user_bad_mask(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = login(client, "").await;
+            let status = client
+                .update_user(with_bearer(UpdateUserRequest {
+                    user: Some(User::default()),
+                    update_mask: Some(FieldMask { paths: vec!["password".to_string()] }),
+                    version: 1,
+                }, &auth.jwt))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            println!("test_update_user_bad_mask: Ok");
+        }
+
+        pub async fn test_other_users_profile(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let other = new_user_login(client, "profile-other").await;
+            let other_uid = client
+                .get_user(with_bearer(GetUserRequest { uid: "".to_string() }, &other.jwt))
+                .await
+                .unwrap()
+                .into_inner()
+                .user
+                .unwrap()
+                .uid;
+
+            let auth = login(client, "").await;
+            let user = client
+                .get_user(with_bearer(GetUserRequest { uid: other_uid.clone() }, &auth.jwt))
+                .await
+                .unwrap()
+                .into_inner()
+                .user
+                .unwrap();
+            assert_eq!(user.username, "profile-other");
+            assert!(user.location.is_none());
+
+            let status = client
+                .delete_user(with_bearer(DeleteUserRequest { uid: other_uid }, &auth.jwt))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+            println!("test_other_users_profile: Ok");
+        }
+
+        pub async fn test_delete_user(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = new_user_login(client, "profile-delete").await;
+            // Only admins, a bearer token alone must not delete the account
+            let status = client
+                .delete_user(with_bearer(DeleteUserRequest { uid: "".to_string() }, &auth.jwt))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+            client
+                .delete_account(with_bearer(DeleteAccountRequest { password: "Swordfish-42".to_string() }, &auth.jwt))
+                .await
+                .unwrap();
+
+            let response = client
+                .validate_token(tonic::Request::new(ValidateTokenRequest { token: auth.jwt }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.status, TokenStatus::Revoked as i32);
+
+            let status = client
+                .auth(tonic::Request::new(AuthRequest {
+                    username: "profile-delete".to_string(),
+                    password: "Swordfish-42".to_string(),
+                    device: "".to_string(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+            println!("test_delete_user: Ok");
+        }
+
+        pub async fn test_delete_account(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = new_user_login(client, "profile-delete-account").await;
+            let status = client
+                .delete_account(with_bearer(DeleteAccountRequest { password: "Swordfish-43".to_string() }, &auth.jwt))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+            client
+                .delete_account(with_bearer(DeleteAccountRequest { password: "Swordfish-42".to_string() }, &auth.jwt))
+                .await
+                .unwrap();
+            let status = client
+                .auth(tonic::Request::new(AuthRequest {
+                    username: "profile-delete-account".to_string(),
+                    password: "Swordfish-42".to_string(),
+                    device: "".to_string(),
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+            println!("test_delete_account: Ok");
+        }
+
+        pub async fn test_export_my_data(client: &mut UserServiceClient<tonic::transport::Channel>) {
+            let auth = new_user_login(client, "profile-export").await;
+            let mut stream = client
+                .export_my_data(with_bearer(ExportMyDataRequest {}, &auth.jwt))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut sections = vec![];
+            while let Some(response) = stream.message().await.unwrap() {
+                // Secrets never leave the service
+                assert!(!response.json.contains("$argon2"));
+                assert!(!response.json.contains("Swordfish-42"));
+                if response.section == "profile" {
+                    assert!(response.json.contains("\"username\":\"profile-export\""));
+                }
+                sections.push(response.section);
+            }
+            assert!(sections.contains(&"profile".to_string()));
+            assert!(sections.contains(&"sessions".to_string()));
+            println!("test_export_my_data: Ok");
+        }
+    }
 }
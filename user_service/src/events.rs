@@ -0,0 +1,336 @@
+use super::store::outbox::PendingEvent;
+use super::store::profiles::gender_name;
+use super::store::{StoredUser, UserStore};
+use super::token::now;
+use super::user::{Location, User};
+use kafka::producer::{Producer, Record, RequiredAcks};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::spawn_blocking;
+use tokio::time::delay_for;
+use uuid::Uuid;
+
+const KAFKA_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+const OUTBOX_BATCH: usize = 100;
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/*
+Published whenever a user changes so other services (recommendations)
+can keep their copy up to date. Serialized as JSON, one event per
+message: `{"id": .., "occurred_at": .., "type": "UserCreated", ..}`.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub id: String, // unique per event, consumers can use it to drop duplicates
+    pub occurred_at: u64,
+    #[serde(flatten)]
+    pub payload: UserEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum UserEvent {
+    UserCreated {
+        user: EventUser,
+    },
+    UserUpdated {
+        user: EventUser,
+    },
+    LocationChanged {
+        uid: String,
+        location: EventLocation,
+    },
+    UserDeleted {
+        uid: String,
+    },
+}
+
+// The public part of a profile, never the password or anything auth related
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventUser {
+    pub uid: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub age: i32,
+    pub gender: String, // Gender variant name, e.g. "Female"
+    pub bio: String,
+    pub location: EventLocation,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Event {
+    pub fn new(payload: UserEvent) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            occurred_at: now(),
+            payload,
+        }
+    }
+
+    // Events of one user share a key so they stay ordered within a partition
+    pub fn key(&self) -> &str {
+        match &self.payload {
+            UserEvent::UserCreated { user } | UserEvent::UserUpdated { user } => &user.uid,
+            UserEvent::LocationChanged { uid, .. } | UserEvent::UserDeleted { uid } => uid,
+        }
+    }
+}
+
+impl From<&User> for EventUser {
+    fn from(user: &User) -> Self {
+        Self {
+            uid: user.uid.clone(),
+            first_name: user.first_name.clone(),
+            last_name: user.last_name.clone(),
+            age: user.age,
+            gender: gender_name(user.gender).to_string(),
+            bio: user.bio.clone(),
+            location: user
+                .location
+                .as_ref()
+                .map(EventLocation::from)
+                .unwrap_or(EventLocation {
+                    latitude: 0.0,
+                    longitude: 0.0,
+                }),
+        }
+    }
+}
+
+impl From<&StoredUser> for EventUser {
+    fn from(user: &StoredUser) -> Self {
+        Self {
+            uid: user.uid.clone(),
+            first_name: user.first_name.clone(),
+            last_name: user.last_name.clone(),
+            age: user.age,
+            gender: gender_name(user.gender).to_string(),
+            bio: user.bio.clone(),
+            location: EventLocation::from(&user.location),
+        }
+    }
+}
+
+impl From<&Location> for EventLocation {
+    fn from(location: &Location) -> Self {
+        Self {
+            latitude: location.latitude,
+            longitude: location.longitude,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PublishError(String);
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "publishing event: {}", self.0)
+    }
+}
+
+impl Error for PublishError {}
+
+#[tonic::async_trait]
+pub trait EventPublisher: Send + Sync {
+    async fn publish(&self, event: &Event) -> Result<(), PublishError>;
+}
+
+// Drops events after logging them, for running without consumers
+pub struct LogPublisher;
+
+#[tonic::async_trait]
+impl EventPublisher for LogPublisher {
+    async fn publish(&self, event: &Event) -> Result<(), PublishError> {
+        info!("Event {}: {:?}", event.id, event.payload);
+        Ok(())
+    }
+}
+
+// Produces to a Kafka topic, keyed by uid
+pub struct KafkaPublisher {
+    producer: Arc<Mutex<Producer>>,
+    topic: String,
+}
+
+impl KafkaPublisher {
+    pub fn new(brokers: Vec<String>, topic: String) -> Result<Self, PublishError> {
+        let producer = Producer::from_hosts(brokers)
+            .with_ack_timeout(KAFKA_ACK_TIMEOUT)
+            .with_required_acks(RequiredAcks::All)
+            .create()
+            .map_err(|err| PublishError(err.to_string()))?;
+        Ok(Self {
+            producer: Arc::new(Mutex::new(producer)),
+            topic,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl EventPublisher for KafkaPublisher {
+    async fn publish(&self, event: &Event) -> Result<(), PublishError> {
+        let value = serde_json::to_string(event).map_err(|err| PublishError(err.to_string()))?;
+        let key = event.key().to_string();
+        let topic = self.topic.clone();
+        let producer = self.producer.clone();
+
+        // The kafka client is blocking
+        spawn_blocking(move || {
+            producer
+                .lock()
+                .unwrap()
+                .send(&Record::from_key_value(&topic, key, value))
+                .map_err(|err| PublishError(err.to_string()))
+        })
+        .await
+        .map_err(|err| PublishError(err.to_string()))?
+    }
+}
+
+// Appends one JSON event per line
+pub struct FilePublisher {
+    path: PathBuf,
+}
+
+impl FilePublisher {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[tonic::async_trait]
+impl EventPublisher for FilePublisher {
+    async fn publish(&self, event: &Event) -> Result<(), PublishError> {
+        let line = serde_json::to_string(event).map_err(|err| PublishError(err.to_string()))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| PublishError(err.to_string()))?;
+        writeln!(file, "{}", line).map_err(|err| PublishError(err.to_string()))
+    }
+}
+
+// Hands events to a receiver in the same process, for tests and embedding
+pub struct ChannelPublisher {
+    sender: UnboundedSender<Event>,
+}
+
+impl ChannelPublisher {
+    pub fn new() -> (Self, UnboundedReceiver<Event>) {
+        let (sender, receiver) = unbounded_channel();
+        (Self { sender }, receiver)
+    }
+}
+
+#[tonic::async_trait]
+impl EventPublisher for ChannelPublisher {
+    async fn publish(&self, event: &Event) -> Result<(), PublishError> {
+        self.sender
+            .send(event.clone())
+            .map_err(|_| PublishError("receiver dropped".to_string()))
+    }
+}
+
+/*
+Drains the outbox to `publisher`, runs until the process exits. An event
+is removed only after it was published, so it's published at least once;
+consumers drop repeats by event id. A failed publish ends the batch so a
+user's later events don't overtake it, though the outbox order itself is
+only as good as the writers' clocks.
+*/
+pub async fn relay_outbox(store: UserStore, publisher: Box<dyn EventPublisher>) {
+    loop {
+        let events = match store.pending_events(OUTBOX_BATCH).await {
+            Ok(events) => events,
+            Err(err) => {
+                warn!("Err reading outbox: {}", err);
+                vec![]
+            }
+        };
+        let mut published = 0;
+        for PendingEvent { sequence, event } in events {
+            if let Err(err) = publisher.publish(&event).await {
+                warn!("Err relaying event {}: {}", event.id, err);
+                break;
+            }
+            if let Err(err) = store.remove_event(&sequence).await {
+                warn!("Err removing relayed event {}: {}", event.id, err);
+                break;
+            }
+            published += 1;
+        }
+        // A full batch means more are waiting
+        if published < OUTBOX_BATCH {
+            delay_for(OUTBOX_POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::user::Gender;
+    use super::*;
+
+    fn location_changed() -> Event {
+        Event::new(UserEvent::LocationChanged {
+            uid: "uid-1".to_string(),
+            location: EventLocation {
+                latitude: 47.6,
+                longitude: -122.3,
+            },
+        })
+    }
+
+    #[test]
+    fn serializes_with_type_tag() {
+        let event = location_changed();
+        let json: serde_json::Value = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "LocationChanged");
+        assert_eq!(json["uid"], "uid-1");
+        assert_eq!(json["location"]["latitude"], 47.6);
+        assert_eq!(json["id"], event.id.as_str());
+    }
+
+    #[test]
+    fn round_trips() {
+        let event = Event::new(UserEvent::UserCreated {
+            user: EventUser::from(&User {
+                uid: "uid-1".to_string(),
+                first_name: "Test".to_string(),
+                gender: Gender::Female as i32,
+                ..Default::default()
+            }),
+        });
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.contains("\"gender\":\"Female\""));
+        assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), event);
+    }
+
+    #[test]
+    fn keyed_by_uid() {
+        assert_eq!(location_changed().key(), "uid-1");
+    }
+
+    #[tokio::test]
+    async fn channel_publisher_delivers() {
+        let (publisher, mut receiver) = ChannelPublisher::new();
+        let event = location_changed();
+        publisher.publish(&event).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), event);
+    }
+}
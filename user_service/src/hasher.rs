@@ -0,0 +1,243 @@
+use super::token::number_from_env;
+use argon2::{Config, ThreadMode, Variant, Version};
+use rand::RngCore;
+use std::env;
+use std::error::Error;
+use std::fmt;
+
+const DEFAULT_BCRYPT_COST: u32 = 10;
+// OWASP recommended minimum for Argon2id
+const DEFAULT_ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_ARGON2_ITERATIONS: u32 = 2;
+const DEFAULT_ARGON2_LANES: u32 = 1;
+const ARGON2_SALT_LEN: usize = 16;
+const ARGON2_HASH_LEN: u32 = 32;
+
+#[derive(Debug)]
+pub enum HashError {
+    UnknownFormat,
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashError::UnknownFormat => write!(f, "unrecognised password hash format"),
+            HashError::Bcrypt(err) => write!(f, "bcrypt: {}", err),
+            HashError::Argon2(err) => write!(f, "argon2: {}", err),
+        }
+    }
+}
+
+impl Error for HashError {}
+
+impl From<bcrypt::BcryptError> for HashError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        HashError::Bcrypt(err.to_string())
+    }
+}
+
+impl From<argon2::Error> for HashError {
+    fn from(err: argon2::Error) -> Self {
+        HashError::Argon2(err.to_string())
+    }
+}
+
+/*
+Hashes new passwords. Hashes are self describing (modular crypt / PHC
+strings) so `verify_password` works for any supported algorithm, and
+`is_current` tells whether a stored hash should be upgraded to the
+configured algorithm and parameters.
+*/
+pub trait PasswordHasher: Send + Sync {
+    fn hash(&self, password: &str) -> Result<String, HashError>;
+    fn is_current(&self, hash: &str) -> bool;
+}
+
+// PASSWORD_HASH picks `argon2id` (the default) or `bcrypt`, see the
+// from_env of each hasher for their parameters
+pub fn hasher_from_env() -> Box<dyn PasswordHasher> {
+    let algorithm = env::var("PASSWORD_HASH").unwrap_or_else(|_| "argon2id".to_string());
+    match algorithm.as_str() {
+        "argon2id" => Box::new(Argon2Hasher::from_env()),
+        "bcrypt" => Box::new(BcryptHasher::from_env()),
+        other => panic!("PASSWORD_HASH must be argon2id or bcrypt: {}", other),
+    }
+}
+
+pub fn verify_password(password: &str, hash: &str) -> Result<bool, HashError> {
+    if is_bcrypt(hash) {
+        Ok(bcrypt::verify(password, hash)?)
+    } else if hash.starts_with("$argon2") {
+        Ok(argon2::verify_encoded(hash, password.as_bytes())?)
+    } else {
+        Err(HashError::UnknownFormat)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BcryptHasher {
+    pub cost: u32,
+}
+
+impl Default for BcryptHasher {
+    fn default() -> Self {
+        Self {
+            cost: DEFAULT_BCRYPT_COST,
+        }
+    }
+}
+
+impl BcryptHasher {
+    // PASSWORD_BCRYPT_COST overrides the default
+    pub fn from_env() -> Self {
+        Self {
+            cost: number_from_env("PASSWORD_BCRYPT_COST").unwrap_or(DEFAULT_BCRYPT_COST),
+        }
+    }
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        Ok(bcrypt::hash(password, self.cost)?)
+    }
+
+    // $2b$<cost>$<salt and hash>
+    fn is_current(&self, hash: &str) -> bool {
+        is_bcrypt(hash)
+            && hash
+                .split('$')
+                .nth(2)
+                .and_then(|cost| cost.parse::<u32>().ok())
+                == Some(self.cost)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Argon2Hasher {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+}
+
+impl Default for Argon2Hasher {
+    fn default() -> Self {
+        Self {
+            memory_kib: DEFAULT_ARGON2_MEMORY_KIB,
+            iterations: DEFAULT_ARGON2_ITERATIONS,
+            lanes: DEFAULT_ARGON2_LANES,
+        }
+    }
+}
+
+impl Argon2Hasher {
+    // PASSWORD_ARGON2_MEMORY_KIB, PASSWORD_ARGON2_ITERATIONS and
+    // PASSWORD_ARGON2_LANES override the defaults
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            memory_kib: number_from_env("PASSWORD_ARGON2_MEMORY_KIB").unwrap_or(default.memory_kib),
+            iterations: number_from_env("PASSWORD_ARGON2_ITERATIONS").unwrap_or(default.iterations),
+            lanes: number_from_env("PASSWORD_ARGON2_LANES").unwrap_or(default.lanes),
+        }
+    }
+
+    fn config(&self) -> Config<'static> {
+        Config {
+            variant: Variant::Argon2id,
+            version: Version::Version13,
+            mem_cost: self.memory_kib,
+            time_cost: self.iterations,
+            lanes: self.lanes,
+            thread_mode: ThreadMode::Sequential,
+            secret: &[],
+            ad: &[],
+            hash_length: ARGON2_HASH_LEN,
+        }
+    }
+}
+
+impl PasswordHasher for Argon2Hasher {
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        let mut salt = [0u8; ARGON2_SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Ok(argon2::hash_encoded(
+            password.as_bytes(),
+            &salt,
+            &self.config(),
+        )?)
+    }
+
+    // $argon2id$v=19$m=<memory>,t=<iterations>,p=<lanes>$<salt>$<hash>
+    fn is_current(&self, hash: &str) -> bool {
+        let mut fields = hash.split('$').skip(1);
+        if fields.next() != Some("argon2id") || fields.next() != Some("v=19") {
+            return false;
+        }
+        let expected = format!(
+            "m={},t={},p={}",
+            self.memory_kib, self.iterations, self.lanes
+        );
+        fields.next() == Some(expected.as_str())
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Cheap parameters, the tests are about formats not strength
+    fn argon2() -> Argon2Hasher {
+        Argon2Hasher {
+            memory_kib: 64,
+            iterations: 1,
+            lanes: 1,
+        }
+    }
+
+    #[test]
+    fn argon2_round_trip() {
+        let hash = argon2().hash("Swordfish-42").unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+        assert!(verify_password("Swordfish-42", &hash).unwrap());
+        assert!(!verify_password("Swordfish-43", &hash).unwrap());
+    }
+
+    #[test]
+    fn bcrypt_round_trip() {
+        let hash = BcryptHasher { cost: 4 }.hash("Swordfish-42").unwrap();
+        assert!(verify_password("Swordfish-42", &hash).unwrap());
+        assert!(!verify_password("Swordfish-43", &hash).unwrap());
+    }
+
+    #[test]
+    fn argon2_outdated_parameters_are_not_current() {
+        let hash = argon2().hash("Swordfish-42").unwrap();
+        assert!(argon2().is_current(&hash));
+        let stronger = Argon2Hasher {
+            iterations: 2,
+            ..argon2()
+        };
+        assert!(!stronger.is_current(&hash));
+    }
+
+    #[test]
+    fn bcrypt_outdated_cost_is_not_current() {
+        let hash = BcryptHasher { cost: 4 }.hash("Swordfish-42").unwrap();
+        assert!(BcryptHasher { cost: 4 }.is_current(&hash));
+        assert!(!BcryptHasher { cost: 5 }.is_current(&hash));
+        assert!(!argon2().is_current(&hash));
+    }
+
+    #[test]
+    fn rejects_unknown_format() {
+        assert!(verify_password("Swordfish-42", "plaintext").is_err());
+    }
+}
@@ -1 +1,11 @@
+pub mod events;
+pub mod hasher;
+pub mod lockout;
+pub mod notify;
+pub mod password;
+pub mod recommendation;
+pub mod service;
+pub mod store;
+pub mod token;
+pub mod totp;
 pub mod user;
\ No newline at end of file
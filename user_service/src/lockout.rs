@@ -0,0 +1,88 @@
+use super::token::{number_from_env, seconds_from_env};
+use std::time::Duration;
+
+const MAX_DOUBLINGS: u32 = 20;
+
+/*
+Exponential backoff for failed Auth attempts: the first `free_attempts`
+failures within `window` cost nothing, after that every failure locks
+the key for `base_lockout` doubled per extra failure, up to `max_lockout`
+*/
+#[derive(Debug, Clone)]
+pub struct LockoutPolicy {
+    pub free_attempts: u64,
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+    pub window: Duration,
+}
+
+impl LockoutPolicy {
+    pub fn per_username() -> Self {
+        Self {
+            free_attempts: 5,
+            base_lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(60 * 60),
+            window: Duration::from_secs(60 * 60),
+        }
+    }
+
+    // Looser than per_username, many users can sit behind one NAT
+    pub fn per_address() -> Self {
+        Self {
+            free_attempts: 20,
+            ..Self::per_username()
+        }
+    }
+
+    // <prefix>_FREE_ATTEMPTS, <prefix>_BASE_LOCKOUT_SEC, <prefix>_MAX_LOCKOUT_SEC
+    // and <prefix>_WINDOW_SEC override the given defaults
+    pub fn from_env(prefix: &str, default: Self) -> Self {
+        Self {
+            free_attempts: number_from_env(&format!("{}_FREE_ATTEMPTS", prefix))
+                .unwrap_or(default.free_attempts),
+            base_lockout: seconds_from_env(&format!("{}_BASE_LOCKOUT_SEC", prefix))
+                .unwrap_or(default.base_lockout),
+            max_lockout: seconds_from_env(&format!("{}_MAX_LOCKOUT_SEC", prefix))
+                .unwrap_or(default.max_lockout),
+            window: seconds_from_env(&format!("{}_WINDOW_SEC", prefix)).unwrap_or(default.window),
+        }
+    }
+
+    pub fn lockout_for(&self, failures: u64) -> Option<Duration> {
+        if failures <= self.free_attempts {
+            return None;
+        }
+        let doublings = (failures - self.free_attempts - 1).min(MAX_DOUBLINGS as u64) as u32;
+        Some(
+            self.base_lockout
+                .checked_mul(2u32.pow(doublings))
+                .unwrap_or(self.max_lockout)
+                .min(self.max_lockout),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Lockout {
+    pub per_username: LockoutPolicy,
+    pub per_address: LockoutPolicy,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self {
+            per_username: LockoutPolicy::per_username(),
+            per_address: LockoutPolicy::per_address(),
+        }
+    }
+}
+
+impl Lockout {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            per_username: LockoutPolicy::from_env("AUTH_USERNAME", default.per_username),
+            per_address: LockoutPolicy::from_env("AUTH_ADDRESS", default.per_address),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use log::info;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/*
+Delivers out of band messages to users. Real deployments would send
+email or SMS, the implementations here are for running locally.
+*/
+pub trait Notifier: Send + Sync {
+    fn password_reset(&self, username: &str, token: &str) -> io::Result<()>;
+}
+
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn password_reset(&self, username: &str, token: &str) -> io::Result<()> {
+        info!("Password reset for {}: token {}", username, token);
+        Ok(())
+    }
+}
+
+// Appends one `<username> <token>` line per notification
+pub struct FileNotifier {
+    path: PathBuf,
+}
+
+impl FileNotifier {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Notifier for FileNotifier {
+    fn password_reset(&self, username: &str, token: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{} {}", username, token)
+    }
+}
@@ -0,0 +1,157 @@
+use super::token::number_from_env;
+use std::collections::HashSet;
+
+// One entry per line, compared case insensitively
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+const DEFAULT_MIN_LENGTH: usize = 8;
+const DEFAULT_MIN_CHARACTER_CLASSES: usize = 2;
+// bcrypt only looks at the first 72 bytes
+const MAX_LENGTH: usize = 72;
+
+/*
+Rules a new password has to pass before it is hashed. Character classes
+are lowercase, uppercase, digits and everything else, a password needs
+at least `min_character_classes` of them.
+*/
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub min_character_classes: usize,
+    blocked: HashSet<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: DEFAULT_MIN_LENGTH,
+            min_character_classes: DEFAULT_MIN_CHARACTER_CLASSES,
+            blocked: COMMON_PASSWORDS
+                .lines()
+                .map(|line| line.trim().to_lowercase())
+                .filter(|line| !line.is_empty())
+                .collect(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    // PASSWORD_MIN_LENGTH and PASSWORD_MIN_CHARACTER_CLASSES override the defaults
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            min_length: number_from_env("PASSWORD_MIN_LENGTH").unwrap_or(default.min_length),
+            min_character_classes: number_from_env("PASSWORD_MIN_CHARACTER_CLASSES")
+                .unwrap_or(default.min_character_classes),
+            ..default
+        }
+    }
+
+    // Every rule `password` breaks, empty when it is acceptable
+    pub fn violations(&self, username: &str, password: &str) -> Vec<String> {
+        let mut violations = vec![];
+        let length = password.chars().count();
+        if length < self.min_length {
+            violations.push(format!(
+                "must be at least {} characters long",
+                self.min_length
+            ));
+        }
+        if password.len() > MAX_LENGTH {
+            violations.push(format!("must be at most {} bytes long", MAX_LENGTH));
+        }
+        if character_classes(password) < self.min_character_classes {
+            violations.push(format!(
+                "must contain at least {} of lowercase letters, uppercase letters, digits and symbols",
+                self.min_character_classes
+            ));
+        }
+        let lowercase = password.to_lowercase();
+        if self.blocked.contains(&lowercase) {
+            violations.push("is too common".to_string());
+        }
+        if !username.is_empty() && lowercase.contains(&username.to_lowercase()) {
+            violations.push("must not contain the username".to_string());
+        }
+        violations
+    }
+}
+
+fn character_classes(password: &str) -> usize {
+    let mut classes = [false; 4];
+    for c in password.chars() {
+        let class = if c.is_lowercase() {
+            0
+        } else if c.is_uppercase() {
+            1
+        } else if c.is_numeric() {
+            2
+        } else {
+            3
+        };
+        classes[class] = true;
+    }
+    classes.iter().filter(|&&present| present).count()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accepts_good_password() {
+        let policy = PasswordPolicy::default();
+        assert!(policy.violations("alice", "Swordfish-42").is_empty());
+    }
+
+    #[test]
+    fn rejects_empty_password() {
+        let policy = PasswordPolicy::default();
+        assert!(!policy.violations("alice", "").is_empty());
+    }
+
+    #[test]
+    fn rejects_short_password() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(
+            policy.violations("alice", "Ab-1"),
+            vec!["must be at least 8 characters long".to_string()]
+        );
+    }
+
+    #[test]
+    fn counts_character_classes() {
+        assert_eq!(character_classes("abcdefgh"), 1);
+        assert_eq!(character_classes("abcdEFGH"), 2);
+        assert_eq!(character_classes("abcD1234"), 3);
+        assert_eq!(character_classes("abC12-!é"), 4);
+    }
+
+    #[test]
+    fn rejects_single_character_class() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(policy.violations("alice", "horsebatterystaple").len(), 1);
+    }
+
+    #[test]
+    fn rejects_common_password_any_case() {
+        let policy = PasswordPolicy::default();
+        assert!(policy
+            .violations("alice", "PASSWORD123")
+            .contains(&"is too common".to_string()));
+    }
+
+    #[test]
+    fn rejects_username_in_password() {
+        let policy = PasswordPolicy::default();
+        assert!(policy
+            .violations("Alice", "xx-alice-2020")
+            .contains(&"must not contain the username".to_string()));
+    }
+
+    #[test]
+    fn rejects_password_over_bcrypt_limit() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(policy.violations("alice", &"Ab1-".repeat(19)).len(), 1);
+    }
+}
@@ -0,0 +1 @@
+tonic::include_proto!("recommendation_svc");
\ No newline at end of file
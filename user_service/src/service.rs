@@ -0,0 +1,1293 @@
+use super::events::{Event, EventLocation, EventUser, UserEvent};
+use super::hasher::{verify_password, Argon2Hasher, PasswordHasher};
+use super::lockout::{Lockout, LockoutPolicy};
+use super::notify::{LogNotifier, Notifier};
+use super::password::PasswordPolicy;
+use super::recommendation::recommendation_service_client::RecommendationServiceClient;
+use super::recommendation::ExportUserDataRequest;
+use super::store::profiles::ProfileUpdate;
+use super::store::resets::PasswordReset;
+use super::store::sessions::StoredSession;
+use super::store::{StoreError, StoredUser, UserStore};
+use super::token::{
+    hash_secret, now, now_millis, random_secret, Claims, RefreshToken, TokenIssuer, ADMIN_ROLE,
+};
+use super::totp;
+use super::user::bad_request::FieldViolation;
+use super::user::user_service_server::UserService;
+use super::user::{
+    AuthRequest, AuthResponse, BadRequest, ChangePasswordRequest, ChangePasswordResponse,
+    ConfirmTotpRequest, ConfirmTotpResponse, DeleteAccountRequest, DeleteAccountResponse,
+    DeleteUserRequest, DeleteUserResponse, EnrollTotpRequest, EnrollTotpResponse,
+    ExportMyDataRequest, ExportMyDataResponse, Gender, GetJwksRequest, GetJwksResponse,
+    GetUserRequest, GetUserResponse, ListSessionsRequest, ListSessionsResponse, Location,
+    LogoutRequest, LogoutResponse, NewUserRequest, NewUserResponse, RefreshTokenRequest,
+    RequestPasswordResetRequest, RequestPasswordResetResponse, ResetPasswordRequest,
+    ResetPasswordResponse, RevokeSessionRequest, RevokeSessionResponse, Session, TokenStatus,
+    UnlockAccountRequest, UnlockAccountResponse, UpdateUserRequest, UpdateUserResponse, User,
+    ValidateTokenRequest, ValidateTokenResponse, VerifySecondFactorRequest,
+};
+use bytes::Bytes;
+use futures::stream::{self, Iter};
+use log::{info, warn};
+use prost::Message;
+use serde_json::json;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::vec::IntoIter;
+use tokio::task::spawn_blocking;
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::{Code, Request, Response, Status};
+use uuid::Uuid;
+
+const RESET_TOKEN_LIFETIME_SEC: u64 = 60 * 60; // 1 hour in seconds
+const TOTP_ISSUER: &str = "date-app"; // shown by authenticator apps
+
+pub struct MainUserService {
+    store: UserStore,
+    tokens: TokenIssuer,
+    lockout: Lockout,
+    notifier: Box<dyn Notifier>,
+    password_policy: PasswordPolicy,
+    hasher: Arc<dyn PasswordHasher>,
+    recommendations: Option<RecommendationServiceClient<Channel>>,
+}
+
+impl MainUserService {
+    pub fn new(store: UserStore, tokens: TokenIssuer) -> Self {
+        Self {
+            store,
+            tokens,
+            lockout: Lockout::default(),
+            notifier: Box::new(LogNotifier),
+            password_policy: PasswordPolicy::default(),
+            hasher: Arc::new(Argon2Hasher::default()),
+            recommendations: None,
+        }
+    }
+
+    pub fn with_lockout(mut self, lockout: Lockout) -> Self {
+        self.lockout = lockout;
+        self
+    }
+
+    pub fn with_notifier(mut self, notifier: Box<dyn Notifier>) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    pub fn with_password_policy(mut self, password_policy: PasswordPolicy) -> Self {
+        self.password_policy = password_policy;
+        self
+    }
+
+    pub fn with_password_hasher(mut self, hasher: Box<dyn PasswordHasher>) -> Self {
+        self.hasher = hasher.into();
+        self
+    }
+
+    // Without it data exports only cover what the user service holds
+    pub fn with_recommendations(mut self, client: RecommendationServiceClient<Channel>) -> Self {
+        self.recommendations = Some(client);
+        self
+    }
+
+    // Hashing is slow on purpose, so it runs on the blocking pool rather than
+    // holding up the other requests of the executor thread
+    async fn hash_password(&self, password: &str) -> Result<String, Status> {
+        let hasher = self.hasher.clone();
+        let password = password.to_string();
+        let hashed = spawn_blocking(move || hasher.hash(&password).map_err(|err| err.to_string()))
+            .await
+            .unwrap_or_else(|err| Err(err.to_string()));
+        hashed.map_err(|err| {
+            info!("Err hashing password: {}", err);
+            Status::internal("internal server error")
+        })
+    }
+
+    async fn password_matches(&self, password: &str, hash: &str) -> Result<bool, Status> {
+        let password = password.to_string();
+        let hash = hash.to_string();
+        let matches = spawn_blocking(move || {
+            verify_password(&password, &hash).map_err(|err| err.to_string())
+        })
+        .await
+        .unwrap_or_else(|err| Err(err.to_string()));
+        matches.map_err(|err| {
+            info!("Err verifying password: {}", err);
+            Status::internal("internal server error")
+        })
+    }
+
+    // Rewrites a hash made with an older algorithm or parameters, the
+    // plaintext is only available right after a successful login
+    async fn upgrade_password_hash(&self, username: &str, password: &str, hash: &str) {
+        if self.hasher.is_current(hash) {
+            return;
+        }
+        let upgraded = match self.hash_password(password).await {
+            Ok(upgraded) => upgraded,
+            Err(_) => return,
+        };
+        match self
+            .store
+            .replace_password_hash(username, hash, &upgraded)
+            .await
+        {
+            Ok(()) => info!("Upgraded password hash of {}", username),
+            // The password changed since we read it, the new hash is already current
+            Err(StoreError::ConditionFailed) => (),
+            Err(err) => info!("Err storing rehashed password of {}: {}", username, err),
+        }
+    }
+
+    // Has to run before hashing, the hash of an empty password isn't empty
+    fn check_password(&self, field: &str, username: &str, password: &str) -> Result<(), Status> {
+        let violations = self.password_policy.violations(username, password);
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(invalid_fields(
+            "password does not meet the password policy",
+            violations
+                .into_iter()
+                .map(|description| FieldViolation {
+                    field: field.to_string(),
+                    description,
+                })
+                .collect(),
+        ))
+    }
+
+    // Verifies a token from `bearer_token` and that it hasn't been revoked
+    async fn authenticate(&self, token: String) -> Result<Claims, Status> {
+        self.verify(token.as_str()).await.map_err(|status| {
+            info!("Token rejected: {:?}", status);
+            Status::unauthenticated("invalid token")
+        })
+    }
+
+    async fn verify(&self, token: &str) -> Result<Claims, TokenStatus> {
+        let claims = self.tokens.validate(token)?;
+        match self
+            .store
+            .is_token_revoked(&claims.jti, &claims.sub, claims.iat_ms, now())
+            .await
+        {
+            Ok(false) => Ok(claims),
+            Ok(true) => Err(TokenStatus::Revoked),
+            Err(err) => {
+                // Fail closed, a token we can't check is not a token we trust
+                info!("Err checking revocation of {}: {}", claims.jti, err);
+                Err(TokenStatus::Revoked)
+            }
+        }
+    }
+
+    async fn user_by_uid(&self, uid: &str) -> Result<StoredUser, Status> {
+        match self.store.get_user_by_uid(uid).await {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(Status::not_found("user not found")),
+            Err(err) => Err(internal(err)),
+        }
+    }
+
+    /*
+    Deletes the row and signs the user out everywhere. The UserDeleted
+    event removes them from recommendations, swipes of others included.
+    */
+    async fn remove_user(&self, user: &StoredUser) -> Result<(), Status> {
+        let event = Event::new(UserEvent::UserDeleted {
+            uid: user.uid.clone(),
+        });
+        match self.store.delete_user(&user.username, &event).await {
+            Ok(()) => (),
+            Err(StoreError::ConditionFailed) => return Err(Status::not_found("user not found")),
+            Err(err) => return Err(internal(err)),
+        }
+        self.revoke_user_tokens(&user.uid).await.map_err(internal)
+    }
+
+    // The recommendation document, None when there is none. `token` is the
+    // caller's, the recommendation service only exports the token's user.
+    async fn recommendation_data(
+        &self,
+        uid: &str,
+        token: &str,
+    ) -> Result<Option<serde_json::Value>, Status> {
+        let mut client = match &self.recommendations {
+            Some(client) => client.clone(),
+            None => return Ok(None),
+        };
+        let mut request = Request::new(ExportUserDataRequest {
+            uid: uid.to_string(),
+        });
+        let authorization = MetadataValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| Status::unauthenticated("invalid token"))?;
+        request
+            .metadata_mut()
+            .insert("authorization", authorization);
+        match client.export_user_data(request).await {
+            Ok(response) => {
+                let response = response.into_inner();
+                let parse = |json: &str| {
+                    serde_json::from_str::<serde_json::Value>(json).map_err(|err| {
+                        info!("Err reading recommendation export: {}", err);
+                        Status::internal("internal server error")
+                    })
+                };
+                Ok(Some(json!({
+                    "shard": response.shard,
+                    "document": parse(&response.document)?,
+                    "swipes": parse(&response.swipes)?,
+                    "matches": parse(&response.matches)?,
+                    "blocks": parse(&response.blocks)?,
+                })))
+            }
+            Err(status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => {
+                warn!("Recommendation export failed: {:?}", status);
+                Err(Status::unavailable(
+                    "recommendation data is unavailable, try again later",
+                ))
+            }
+        }
+    }
+
+    // Bulk revocation: every token issued so far and every session of the user
+    async fn revoke_user_tokens(&self, uid: &str) -> Result<(), StoreError> {
+        let now_ms = now_millis();
+        let config = self.tokens.config();
+        let expires_at = now_ms / 1000 + config.lifetime.as_secs() + config.leeway.as_secs();
+        self.store
+            .set_revocation_watermark(uid, now_ms, expires_at)
+            .await?;
+        self.store.delete_sessions(uid).await
+    }
+
+    // Stores the new password and signs the user out everywhere,
+    // `password` must already have passed `check_password`
+    // Only replaces `current_hash`, the password the caller was checked against
+    async fn replace_password(
+        &self,
+        username: &str,
+        uid: &str,
+        current_hash: &str,
+        password: String,
+    ) -> Result<(), Status> {
+        let hashed = self.hash_password(&password).await?;
+        match self
+            .store
+            .replace_password_hash(username, current_hash, &hashed)
+            .await
+        {
+            Ok(()) => (),
+            Err(StoreError::ConditionFailed) => {
+                return Err(Status::aborted("the password was changed meanwhile"))
+            }
+            Err(err) => return Err(internal(err)),
+        }
+        self.revoke_user_tokens(uid).await.map_err(internal)
+    }
+
+    fn attempt_keys(&self, username: &str, addr: Option<IpAddr>) -> Vec<(String, &LockoutPolicy)> {
+        let mut keys = vec![(username_key(username), &self.lockout.per_username)];
+        if let Some(addr) = addr {
+            keys.push((address_key(addr), &self.lockout.per_address));
+        }
+        keys
+    }
+
+    async fn check_lockout(&self, keys: &[(String, &LockoutPolicy)]) -> Result<(), Status> {
+        let now = now();
+        let mut locked_until = 0;
+        for (key, _) in keys {
+            let attempts = self
+                .store
+                .get_failed_attempts(key)
+                .await
+                .map_err(internal)?;
+            if attempts.expires_at > now {
+                locked_until = locked_until.max(attempts.locked_until);
+            } else if attempts.failures > 0 {
+                // TTL deletes lazily, don't let stale failures count towards a new lockout
+                self.store
+                    .clear_failed_attempts(key)
+                    .await
+                    .map_err(internal)?;
+            }
+        }
+        if locked_until > now {
+            return Err(locked_out(locked_until - now));
+        }
+        Ok(())
+    }
+
+    async fn record_failed_attempt(&self, keys: &[(String, &LockoutPolicy)]) -> Result<(), Status> {
+        let now = now();
+        for (key, policy) in keys {
+            let expires_at = now + policy.window.as_secs();
+            let attempts = self
+                .store
+                .record_failed_attempt(key, expires_at)
+                .await
+                .map_err(internal)?;
+            if let Some(lockout) = policy.lockout_for(attempts.failures) {
+                warn!(
+                    "Locking {} for {}s after {} failed attempts",
+                    key,
+                    lockout.as_secs(),
+                    attempts.failures
+                );
+                let locked_until = now + lockout.as_secs();
+                self.store
+                    .lock_attempts(key, locked_until, expires_at.max(locked_until))
+                    .await
+                    .map_err(internal)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Accepts a TOTP code or one of the recovery codes, each at most once
+    async fn second_factor_matches(&self, user: &StoredUser, code: &str) -> Result<bool, Status> {
+        let secret = match &user.totp_secret {
+            Some(secret) if user.totp_enabled => secret,
+            _ => return Ok(false),
+        };
+        if let Some(step) = totp::verify(secret, code, now()) {
+            return self
+                .store
+                .use_totp_step(&user.username, step)
+                .await
+                .map_err(internal);
+        }
+
+        let code_hash = hash_secret(&totp::normalize_recovery_code(code));
+        let used = self
+            .store
+            .use_recovery_code(&user.username, &code_hash)
+            .await
+            .map_err(internal)?;
+        if used {
+            warn!("Recovery code used: Username: {}", user.username);
+        }
+        Ok(used)
+    }
+
+    async fn start_session(
+        &self,
+        uid: String,
+        roles: Vec<String>,
+        device: String,
+    ) -> Result<AuthResponse, Status> {
+        let now = now();
+        let session_id = Uuid::new_v4().to_string();
+        let refresh_token = RefreshToken::generate(uid.clone(), session_id.clone(), 0);
+        let session = StoredSession {
+            uid,
+            session_id,
+            device,
+            generation: 0,
+            refresh_hash: refresh_token.hash(),
+            roles,
+            created_at: now,
+            last_used_at: now,
+            expires_at: now + self.tokens.config().refresh_lifetime.as_secs(),
+        };
+        self.store.put_session(&session).await.map_err(internal)?;
+        Ok(self.token_pair(&session, &refresh_token))
+    }
+
+    fn token_pair(&self, session: &StoredSession, refresh_token: &RefreshToken) -> AuthResponse {
+        AuthResponse {
+            jwt: self.tokens.issue(
+                session.uid.clone(),
+                session.roles.clone(),
+                session.session_id.clone(),
+            ),
+            refresh_token: refresh_token.to_string(),
+            expires_in: self.tokens.config().lifetime.as_secs(),
+            session_id: session.session_id.clone(),
+            ..Default::default()
+        }
+    }
+
+    async fn revoke_reused_session(&self, session: &StoredSession) -> Status {
+        warn!(
+            "Refresh token reuse on session {} of {}: revoking",
+            session.session_id, session.uid
+        );
+        if let Err(err) = self
+            .store
+            .delete_session(&session.uid, &session.session_id)
+            .await
+        {
+            return internal(err);
+        }
+        Status::unauthenticated("refresh token reuse detected, session revoked")
+    }
+}
+
+// The `authorization: Bearer <jwt>` metadata of a request
+fn bearer_token<T>(request: &Request<T>) -> Result<String, Status> {
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(|token| token.to_string())
+        .ok_or_else(|| Status::unauthenticated("missing bearer token"))
+}
+
+fn is_admin(claims: &Claims) -> bool {
+    claims.roles.iter().any(|role| role == ADMIN_ROLE)
+}
+
+// The uid a request acts on, users may only act on themselves unless they are admins
+fn target_uid(claims: &Claims, uid: &str) -> Result<String, Status> {
+    if uid.is_empty() || uid == claims.sub {
+        return Ok(claims.sub.clone());
+    }
+    if !is_admin(claims) {
+        return Err(Status::permission_denied("admin role required"));
+    }
+    Ok(uid.to_string())
+}
+
+fn valid_location(location: &Location) -> bool {
+    (-90.0..=90.0).contains(&location.latitude) && (-180.0..=180.0).contains(&location.longitude)
+}
+
+// Everything but the password hash
+fn profile(user: StoredUser) -> User {
+    User {
+        first_name: user.first_name,
+        last_name: user.last_name,
+        username: user.username,
+        password: String::new(),
+        uid: user.uid,
+        age: user.age,
+        gender: user.gender,
+        location: Some(user.location),
+        bio: user.bio,
+    }
+}
+
+fn username_key(username: &str) -> String {
+    format!("user#{}", username)
+}
+
+fn address_key(addr: IpAddr) -> String {
+    format!("addr#{}", addr)
+}
+
+fn locked_out(retry_after: u64) -> Status {
+    let mut status = Status::resource_exhausted(format!(
+        "too many failed attempts, retry in {}s",
+        retry_after
+    ));
+    status
+        .metadata_mut()
+        .insert("retry-after", retry_after.to_string().parse().unwrap());
+    status
+}
+
+// invalid_argument with a BadRequest in the status details
+fn invalid_fields(message: &str, field_violations: Vec<FieldViolation>) -> Status {
+    let mut details = Vec::new();
+    BadRequest { field_violations }
+        .encode(&mut details)
+        .unwrap();
+    Status::with_details(Code::InvalidArgument, message, Bytes::from(details))
+}
+
+fn internal(err: StoreError) -> Status {
+    info!("Store err: {}", err);
+    Status::internal("internal server error")
+}
+
+#[tonic::async_trait]
+impl UserService for MainUserService {
+    async fn new_user(
+        &self,
+        request: Request<NewUserRequest>,
+    ) -> Result<Response<NewUserResponse>, Status> {
+        let mut user = match (request.into_inner() as NewUserRequest).user {
+            Some(user) => user,
+            None => return Err(Status::invalid_argument("please provide user")),
+        };
+        if user.username.is_empty() {
+            return Err(invalid_fields(
+                "please provide username",
+                vec![FieldViolation {
+                    field: "user.username".to_string(),
+                    description: "must not be empty".to_string(),
+                }],
+            ));
+        }
+        // Required to place the user in a shard of the recommendation service
+        if !user.location.as_ref().map_or(false, valid_location) {
+            return Err(invalid_fields(
+                "please provide location",
+                vec![FieldViolation {
+                    field: "user.location".to_string(),
+                    description: "must be a valid latitude and longitude".to_string(),
+                }],
+            ));
+        }
+        self.check_password("user.password", &user.username, &user.password)?;
+        user.uid = Uuid::new_v4().to_string();
+        user.password = self.hash_password(&user.password).await?;
+        let event = Event::new(UserEvent::UserCreated {
+            user: EventUser::from(&user),
+        });
+        match self.store.create_user(user, &event).await {
+            Ok(_) => (),
+            Err(StoreError::ConditionFailed) => {
+                return Err(Status::already_exists("user already exists"))
+            }
+            Err(err) => {
+                info!("Err creating user: {}", err);
+                return Err(Status::internal("internal server error"));
+            }
+        }
+        Ok(Response::new(NewUserResponse {}))
+    }
+
+    async fn auth(&self, request: Request<AuthRequest>) -> Result<Response<AuthResponse>, Status> {
+        let addr = request.remote_addr().map(|addr| addr.ip());
+        let auth = request.into_inner();
+        info!("Auth: Username: {} Password: ****", auth.username);
+
+        if auth.username.is_empty() || auth.password.is_empty() {
+            return Err(Status::permission_denied(
+                "Please provide username or password",
+            ));
+        }
+
+        let attempt_keys = self.attempt_keys(&auth.username, addr);
+        self.check_lockout(&attempt_keys).await?;
+
+        let user = match self.store.get_user(auth.username.clone()).await {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                // Unknown usernames count too, otherwise lockouts reveal which exist
+                self.record_failed_attempt(&attempt_keys).await?;
+                return Err(Status::permission_denied("Bad Username or password"));
+            }
+            Err(err) => {
+                info!("Err getting user: {}", err);
+                return Err(Status::internal("internal server error"));
+            }
+        };
+
+        if !self
+            .password_matches(&auth.password, &user.password)
+            .await?
+        {
+            self.record_failed_attempt(&attempt_keys).await?;
+            return Err(Status::permission_denied("Bad Username or password"));
+        }
+        self.upgrade_password_hash(&user.username, &auth.password, &user.password)
+            .await;
+        if user.totp_enabled {
+            // Failed attempts are only cleared once the second factor passes,
+            // otherwise knowing the password would reset the lockout on codes
+            info!("Auth: Username: {} second factor required", user.username);
+            return Ok(Response::new(AuthResponse {
+                second_factor_required: true,
+                challenge_token: self
+                    .tokens
+                    .issue_challenge(user.uid, user.username, auth.device),
+                ..Default::default()
+            }));
+        }
+        info!("Auth: Username: {} Successful", user.username);
+        // The address counter is left alone so one good account can't reset it
+        self.store
+            .clear_failed_attempts(&attempt_keys[0].0)
+            .await
+            .map_err(internal)?;
+        let response = self
+            .start_session(user.uid, user.roles, auth.device)
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn validate_token(
+        &self,
+        request: Request<ValidateTokenRequest>,
+    ) -> Result<Response<ValidateTokenResponse>, Status> {
+        let token = request.into_inner().token;
+        let response = match self.verify(token.as_str()).await {
+            Ok(claims) => ValidateTokenResponse {
+                status: TokenStatus::Valid as i32,
+                claims: Some(claims.into()),
+            },
+            Err(status) => {
+                info!("Token rejected: {:?}", status);
+                ValidateTokenResponse {
+                    status: status as i32,
+                    claims: None,
+                }
+            }
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn get_jwks(
+        &self,
+        _request: Request<GetJwksRequest>,
+    ) -> Result<Response<GetJwksResponse>, Status> {
+        Ok(Response::new(GetJwksResponse {
+            keys: self.tokens.jwks(),
+        }))
+    }
+
+    async fn refresh_token(
+        &self,
+        request: Request<RefreshTokenRequest>,
+    ) -> Result<Response<AuthResponse>, Status> {
+        let presented: RefreshToken = request
+            .into_inner()
+            .refresh_token
+            .parse()
+            .map_err(|_| Status::unauthenticated("invalid refresh token"))?;
+
+        let session = match self
+            .store
+            .get_session(&presented.uid, &presented.session_id)
+            .await
+            .map_err(internal)?
+        {
+            Some(session) if session.expires_at > now() => session,
+            _ => return Err(Status::unauthenticated("invalid refresh token")),
+        };
+
+        // An older generation means the token was already exchanged once,
+        // either the client or whoever stole the token is replaying it
+        if presented.generation < session.generation {
+            return Err(self.revoke_reused_session(&session).await);
+        }
+        if presented.generation != session.generation || presented.hash() != session.refresh_hash {
+            return Err(Status::unauthenticated("invalid refresh token"));
+        }
+
+        let now = now();
+        let next = RefreshToken::generate(
+            session.uid.clone(),
+            session.session_id.clone(),
+            session.generation + 1,
+        );
+        let expires_at = now + self.tokens.config().refresh_lifetime.as_secs();
+        match self
+            .store
+            .rotate_session(&session, &next.hash(), now, expires_at)
+            .await
+        {
+            Ok(()) => (),
+            // Someone exchanged the same token between our read and write
+            Err(StoreError::ConditionFailed) => {
+                return Err(self.revoke_reused_session(&session).await)
+            }
+            Err(err) => return Err(internal(err)),
+        }
+
+        let session = StoredSession {
+            generation: next.generation,
+            refresh_hash: next.hash(),
+            last_used_at: now,
+            expires_at,
+            ..session
+        };
+        Ok(Response::new(self.token_pair(&session, &next)))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        let now = now();
+
+        let mut sessions: Vec<Session> = self
+            .store
+            .list_sessions(&claims.sub)
+            .await
+            .map_err(internal)?
+            .into_iter()
+            // DynamoDB TTL deletes lazily so expired rows can still show up
+            .filter(|session| session.expires_at > now)
+            .map(|session| Session {
+                current: session.session_id == claims.sid,
+                session_id: session.session_id,
+                device: session.device,
+                created_at: session.created_at,
+                last_used_at: session.last_used_at,
+                expires_at: session.expires_at,
+            })
+            .collect();
+        sessions.sort_by(|a, b| b.last_used_at.cmp(&a.last_used_at));
+
+        Ok(Response::new(ListSessionsResponse { sessions }))
+    }
+
+    async fn revoke_session(
+        &self,
+        request: Request<RevokeSessionRequest>,
+    ) -> Result<Response<RevokeSessionResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        let session_id = request.into_inner().session_id;
+        if session_id.is_empty() {
+            return Err(Status::invalid_argument("please provide session_id"));
+        }
+
+        // Keyed by the caller's uid so only their own sessions can be revoked
+        match self.store.delete_session(&claims.sub, &session_id).await {
+            Ok(true) => {
+                info!("Revoked session {} of {}", session_id, claims.sub);
+                Ok(Response::new(RevokeSessionResponse {}))
+            }
+            Ok(false) => Err(Status::not_found("session not found")),
+            Err(err) => Err(internal(err)),
+        }
+    }
+
+    async fn logout(
+        &self,
+        request: Request<LogoutRequest>,
+    ) -> Result<Response<LogoutResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        let logout = request.into_inner();
+
+        self.store
+            .revoke_token(&claims.jti, claims.exp)
+            .await
+            .map_err(internal)?;
+        if logout.all_devices {
+            self.revoke_user_tokens(&claims.sub)
+                .await
+                .map_err(internal)?;
+        } else if !claims.sid.is_empty() {
+            self.store
+                .delete_session(&claims.sub, &claims.sid)
+                .await
+                .map_err(internal)?;
+        }
+        info!(
+            "Logout: {} session {} all devices: {}",
+            claims.sub, claims.sid, logout.all_devices
+        );
+        Ok(Response::new(LogoutResponse {}))
+    }
+
+    async fn unlock_account(
+        &self,
+        request: Request<UnlockAccountRequest>,
+    ) -> Result<Response<UnlockAccountResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        if !is_admin(&claims) {
+            return Err(Status::permission_denied("admin role required"));
+        }
+
+        let unlock = request.into_inner();
+        let addr = match unlock.address.as_str() {
+            "" => None,
+            address => Some(
+                address
+                    .parse::<IpAddr>()
+                    .map_err(|_| Status::invalid_argument("address is not an ip address"))?,
+            ),
+        };
+        let mut keys = vec![];
+        if !unlock.username.is_empty() {
+            keys.push(username_key(&unlock.username));
+        }
+        if let Some(addr) = addr {
+            keys.push(address_key(addr));
+        }
+        if keys.is_empty() {
+            return Err(Status::invalid_argument(
+                "please provide username or address",
+            ));
+        }
+
+        for key in keys {
+            info!("Unlock: {} cleared by {}", key, claims.sub);
+            self.store
+                .clear_failed_attempts(&key)
+                .await
+                .map_err(internal)?;
+        }
+        Ok(Response::new(UnlockAccountResponse {}))
+    }
+
+    async fn change_password(
+        &self,
+        request: Request<ChangePasswordRequest>,
+    ) -> Result<Response<ChangePasswordResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        let addr = request.remote_addr().map(|addr| addr.ip());
+        let change = request.into_inner();
+
+        let user = self.user_by_uid(&claims.sub).await?;
+        let username = user.username.clone();
+
+        // Same lockout as Auth, a stolen token shouldn't allow guessing the password
+        let attempt_keys = self.attempt_keys(&username, addr);
+        self.check_lockout(&attempt_keys).await?;
+        if !self
+            .password_matches(&change.old_password, &user.password)
+            .await?
+        {
+            self.record_failed_attempt(&attempt_keys).await?;
+            return Err(Status::permission_denied("Bad password"));
+        }
+        self.check_password("new_password", &username, &change.new_password)?;
+
+        self.replace_password(&username, &user.uid, &user.password, change.new_password)
+            .await?;
+        info!("Password changed: Username: {}", username);
+        Ok(Response::new(ChangePasswordResponse {}))
+    }
+
+    async fn request_password_reset(
+        &self,
+        request: Request<RequestPasswordResetRequest>,
+    ) -> Result<Response<RequestPasswordResetResponse>, Status> {
+        let username = request.into_inner().username;
+        let user = match self.store.get_user(username.clone()).await {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                info!("Password reset requested for unknown user {}", username);
+                return Ok(Response::new(RequestPasswordResetResponse {}));
+            }
+            Err(err) => return Err(internal(err)),
+        };
+
+        let token = random_secret();
+        let reset = PasswordReset {
+            username: user.username,
+            uid: user.uid,
+            expires_at: now() + RESET_TOKEN_LIFETIME_SEC,
+            password_digest: hash_secret(&user.password),
+        };
+        self.store
+            .put_password_reset(&hash_secret(&token), &reset)
+            .await
+            .map_err(internal)?;
+
+        // Answered the same as for an unknown user, an error would tell them apart
+        if let Err(err) = self.notifier.password_reset(&reset.username, &token) {
+            warn!("Err sending password reset to {}: {}", reset.username, err);
+        }
+        Ok(Response::new(RequestPasswordResetResponse {}))
+    }
+
+    async fn reset_password(
+        &self,
+        request: Request<ResetPasswordRequest>,
+    ) -> Result<Response<ResetPasswordResponse>, Status> {
+        let reset_request = request.into_inner();
+        let token_hash = hash_secret(&reset_request.token);
+
+        // Checked before the token is consumed so a rejected password doesn't burn it
+        let reset = match self
+            .store
+            .get_password_reset(&token_hash)
+            .await
+            .map_err(internal)?
+        {
+            Some(reset) if reset.expires_at > now() => reset,
+            _ => return Err(Status::permission_denied("invalid or expired reset token")),
+        };
+        // A password set since the reset was requested, through ChangePassword
+        // or another reset, invalidates it
+        let user = match self
+            .store
+            .get_user(reset.username.clone())
+            .await
+            .map_err(internal)?
+        {
+            Some(user) if hash_secret(&user.password) == reset.password_digest => user,
+            _ => return Err(Status::permission_denied("invalid or expired reset token")),
+        };
+        self.check_password("new_password", &reset.username, &reset_request.new_password)?;
+        let reset = match self
+            .store
+            .take_password_reset(&token_hash)
+            .await
+            .map_err(internal)?
+        {
+            Some(reset) if reset.expires_at > now() => reset,
+            _ => return Err(Status::permission_denied("invalid or expired reset token")),
+        };
+
+        self.replace_password(
+            &reset.username,
+            &reset.uid,
+            &user.password,
+            reset_request.new_password,
+        )
+        .await?;
+        info!("Password reset: Username: {}", reset.username);
+        Ok(Response::new(ResetPasswordResponse {}))
+    }
+
+    async fn enroll_totp(
+        &self,
+        request: Request<EnrollTotpRequest>,
+    ) -> Result<Response<EnrollTotpResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        let user = self.user_by_uid(&claims.sub).await?;
+        if user.totp_enabled {
+            return Err(Status::failed_precondition("totp is already enabled"));
+        }
+
+        let secret = totp::generate_secret();
+        match self.store.set_totp_secret(&user.username, &secret).await {
+            Ok(()) => (),
+            Err(StoreError::ConditionFailed) => {
+                return Err(Status::failed_precondition("totp is already enabled"))
+            }
+            Err(err) => return Err(internal(err)),
+        }
+        info!("TOTP enrollment started: Username: {}", user.username);
+        Ok(Response::new(EnrollTotpResponse {
+            otpauth_uri: totp::otpauth_uri(TOTP_ISSUER, &user.username, &secret),
+            secret,
+        }))
+    }
+
+    async fn confirm_totp(
+        &self,
+        request: Request<ConfirmTotpRequest>,
+    ) -> Result<Response<ConfirmTotpResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        let code = request.into_inner().code;
+        let user = self.user_by_uid(&claims.sub).await?;
+        let secret = match user.totp_secret {
+            Some(secret) if !user.totp_enabled => secret,
+            _ => return Err(Status::failed_precondition("no totp enrollment pending")),
+        };
+
+        let step = match totp::verify(&secret, &code, now()) {
+            Some(step) => step,
+            None => return Err(Status::permission_denied("Bad code")),
+        };
+        if !self
+            .store
+            .use_totp_step(&user.username, step)
+            .await
+            .map_err(internal)?
+        {
+            return Err(Status::permission_denied("Bad code"));
+        }
+
+        let recovery_codes = totp::generate_recovery_codes();
+        let recovery_code_hashes = recovery_codes
+            .iter()
+            .map(|code| hash_secret(&totp::normalize_recovery_code(code)))
+            .collect();
+        match self
+            .store
+            .enable_totp(&user.username, &secret, recovery_code_hashes)
+            .await
+        {
+            Ok(()) => (),
+            // EnrollTotp ran again since we read the secret
+            Err(StoreError::ConditionFailed) => {
+                return Err(Status::failed_precondition("totp enrollment changed"))
+            }
+            Err(err) => return Err(internal(err)),
+        }
+        info!("TOTP enabled: Username: {}", user.username);
+        Ok(Response::new(ConfirmTotpResponse { recovery_codes }))
+    }
+
+    async fn verify_second_factor(
+        &self,
+        request: Request<VerifySecondFactorRequest>,
+    ) -> Result<Response<AuthResponse>, Status> {
+        let addr = request.remote_addr().map(|addr| addr.ip());
+        let verify = request.into_inner();
+        let challenge = self
+            .tokens
+            .validate_challenge(&verify.challenge_token)
+            .map_err(|status| {
+                info!("Challenge rejected: {:?}", status);
+                Status::unauthenticated("invalid challenge token")
+            })?;
+
+        // Challenges are single use and die with the user's other tokens
+        match self
+            .store
+            .is_token_revoked(&challenge.jti, &challenge.sub, challenge.iat_ms, now())
+            .await
+        {
+            Ok(false) => (),
+            Ok(true) => return Err(Status::unauthenticated("invalid challenge token")),
+            Err(err) => return Err(internal(err)),
+        }
+
+        let attempt_keys = self.attempt_keys(&challenge.username, addr);
+        self.check_lockout(&attempt_keys).await?;
+        let user = match self.store.get_user(challenge.username.clone()).await {
+            Ok(Some(user)) if user.uid == challenge.sub => user,
+            Ok(_) => return Err(Status::unauthenticated("invalid challenge token")),
+            Err(err) => return Err(internal(err)),
+        };
+        if !self.second_factor_matches(&user, &verify.code).await? {
+            self.record_failed_attempt(&attempt_keys).await?;
+            return Err(Status::permission_denied("Bad code"));
+        }
+
+        self.store
+            .revoke_token(&challenge.jti, challenge.exp)
+            .await
+            .map_err(internal)?;
+        self.store
+            .clear_failed_attempts(&attempt_keys[0].0)
+            .await
+            .map_err(internal)?;
+        info!("Auth: Username: {} Successful", user.username);
+        let response = self
+            .start_session(user.uid, user.roles, challenge.device)
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    async fn get_user(
+        &self,
+        request: Request<GetUserRequest>,
+    ) -> Result<Response<GetUserResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        let uid = match request.into_inner().uid {
+            uid if uid.is_empty() => claims.sub.clone(),
+            uid => uid,
+        };
+
+        let user = self.user_by_uid(&uid).await?;
+        let version = user.version;
+        let mut user = profile(user);
+        // Exact coordinates are only for the user themselves
+        if uid != claims.sub && !is_admin(&claims) {
+            user.location = None;
+        }
+        Ok(Response::new(GetUserResponse {
+            version,
+            user: Some(user),
+        }))
+    }
+
+    async fn update_user(
+        &self,
+        request: Request<UpdateUserRequest>,
+    ) -> Result<Response<UpdateUserResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        let update = request.into_inner();
+        let user = update.user.unwrap_or_default();
+        let uid = target_uid(&claims, &user.uid)?;
+
+        let paths = update
+            .update_mask
+            .map(|mask| mask.paths)
+            .unwrap_or_default();
+        if paths.is_empty() {
+            return Err(invalid_fields(
+                "please provide update_mask",
+                vec![FieldViolation {
+                    field: "update_mask".to_string(),
+                    description: "must list at least one field".to_string(),
+                }],
+            ));
+        }
+        let mut profile_update = ProfileUpdate::default();
+        let mut violations = vec![];
+        for path in paths {
+            match path.as_str() {
+                "first_name" => profile_update.first_name = Some(user.first_name.clone()),
+                "last_name" => profile_update.last_name = Some(user.last_name.clone()),
+                "age" => profile_update.age = Some(user.age),
+                "gender" => profile_update.gender = Some(user.gender),
+                "bio" => profile_update.bio = Some(user.bio.clone()),
+                "location" => match &user.location {
+                    Some(location) if valid_location(location) => {
+                        profile_update.location = Some(location.clone())
+                    }
+                    _ => violations.push(FieldViolation {
+                        field: "user.location".to_string(),
+                        description: "must be a valid latitude and longitude".to_string(),
+                    }),
+                },
+                _ => violations.push(FieldViolation {
+                    field: "update_mask".to_string(),
+                    description: format!("{} can't be updated", path),
+                }),
+            }
+        }
+        if !violations.is_empty() {
+            return Err(invalid_fields("unsupported update_mask paths", violations));
+        }
+
+        let mut updated = self.user_by_uid(&uid).await?;
+        if updated.version != update.version {
+            return Err(Status::aborted(
+                "profile changed since it was read, get it again",
+            ));
+        }
+        // The write is conditional on the version read, so this is what gets stored
+        profile_update.apply(&mut updated);
+
+        let mut events = vec![];
+        if profile_update.first_name.is_some()
+            || profile_update.last_name.is_some()
+            || profile_update.age.is_some()
+            || profile_update.gender.is_some()
+            || profile_update.bio.is_some()
+        {
+            events.push(Event::new(UserEvent::UserUpdated {
+                user: EventUser::from(&updated),
+            }));
+        }
+        if profile_update.location.is_some() {
+            events.push(Event::new(UserEvent::LocationChanged {
+                uid: updated.uid.clone(),
+                location: EventLocation::from(&updated.location),
+            }));
+        }
+        match self
+            .store
+            .update_profile(&updated.username, update.version, profile_update, &events)
+            .await
+        {
+            Ok(()) => {
+                info!(
+                    "Profile updated: Username: {} by {}",
+                    updated.username, claims.sub
+                );
+                Ok(Response::new(UpdateUserResponse {
+                    version: updated.version,
+                    user: Some(profile(updated)),
+                }))
+            }
+            Err(StoreError::ConditionFailed) => Err(Status::aborted(
+                "profile changed since it was read, get it again",
+            )),
+            Err(err) => Err(internal(err)),
+        }
+    }
+
+    async fn delete_user(
+        &self,
+        request: Request<DeleteUserRequest>,
+    ) -> Result<Response<DeleteUserResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        // Users delete themselves through DeleteAccount, which asks for the password
+        if !is_admin(&claims) {
+            return Err(Status::permission_denied(
+                "admin role required, use DeleteAccount to delete your own account",
+            ));
+        }
+        let uid = target_uid(&claims, &request.into_inner().uid)?;
+
+        let user = self.user_by_uid(&uid).await?;
+        self.remove_user(&user).await?;
+        info!(
+            "User deleted: Username: {} by {}",
+            user.username, claims.sub
+        );
+        Ok(Response::new(DeleteUserResponse {}))
+    }
+
+    async fn delete_account(
+        &self,
+        request: Request<DeleteAccountRequest>,
+    ) -> Result<Response<DeleteAccountResponse>, Status> {
+        let claims = self.authenticate(bearer_token(&request)?).await?;
+        let addr = request.remote_addr().map(|addr| addr.ip());
+        let password = request.into_inner().password;
+
+        let user = self.user_by_uid(&claims.sub).await?;
+        // Same lockout as ChangePassword, a stolen token alone can't delete the account
+        let attempt_keys = self.attempt_keys(&user.username, addr);
+        self.check_lockout(&attempt_keys).await?;
+        if !self.password_matches(&password, &user.password).await? {
+            self.record_failed_attempt(&attempt_keys).await?;
+            return Err(Status::permission_denied("Bad password"));
+        }
+
+        self.remove_user(&user).await?;
+        info!("Account deleted: Username: {}", user.username);
+        Ok(Response::new(DeleteAccountResponse {}))
+    }
+
+    type ExportMyDataStream = Iter<IntoIter<Result<ExportMyDataResponse, Status>>>;
+
+    async fn export_my_data(
+        &self,
+        request: Request<ExportMyDataRequest>,
+    ) -> Result<Response<Self::ExportMyDataStream>, Status> {
+        let token = bearer_token(&request)?;
+        let claims = self.authenticate(token.clone()).await?;
+        let user = self.user_by_uid(&claims.sub).await?;
+
+        // Everything but secrets: password hash, TOTP secret, refresh token hashes
+        let mut sections = vec![(
+            "profile",
+            json!({
+                "uid": user.uid,
+                "username": user.username,
+                "first_name": user.first_name,
+                "last_name": user.last_name,
+                "age": user.age,
+                "gender": Gender::from_i32(user.gender).map(|gender| format!("{:?}", gender)),
+                "bio": user.bio,
+                "location": {
+                    "latitude": user.location.latitude,
+                    "longitude": user.location.longitude,
+                },
+                "roles": user.roles,
+                "totp_enabled": user.totp_enabled,
+                "version": user.version,
+            }),
+        )];
+
+        let sessions: Vec<serde_json::Value> = self
+            .store
+            .list_sessions(&user.uid)
+            .await
+            .map_err(internal)?
+            .into_iter()
+            .map(|session| {
+                json!({
+                    "session_id": session.session_id,
+                    "device": session.device,
+                    "created_at": session.created_at,
+                    "last_used_at": session.last_used_at,
+                    "expires_at": session.expires_at,
+                })
+            })
+            .collect();
+        sections.push(("sessions", json!(sessions)));
+
+        if let Some(recommendations) = self.recommendation_data(&user.uid, &token).await? {
+            sections.push(("recommendations", recommendations));
+        }
+
+        info!("Data exported: Username: {}", user.username);
+        let responses: Vec<Result<ExportMyDataResponse, Status>> = sections
+            .into_iter()
+            .map(|(section, json)| {
+                Ok(ExportMyDataResponse {
+                    section: section.to_string(),
+                    json: json.to_string(),
+                })
+            })
+            .collect();
+        Ok(Response::new(stream::iter(responses)))
+    }
+}
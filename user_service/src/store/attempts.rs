@@ -0,0 +1,98 @@
+use super::{number_attr, number_value, string_attr, StoreError, UserStore, ATTEMPT_TABLE};
+use rusoto_dynamodb::{AttributeValue, DeleteItemInput, DynamoDb, GetItemInput, UpdateItemInput};
+use std::collections::HashMap;
+
+/*
+Failed Auth attempts per key, where a key is `user#<username>` or
+`addr#<ip>`. Rows expire `window` after the last failure so old
+failures are forgotten without a cleanup job.
+*/
+#[derive(Debug, Default)]
+pub struct FailedAttempts {
+    pub failures: u64,
+    pub locked_until: u64,
+    pub expires_at: u64,
+}
+
+impl UserStore {
+    pub async fn get_failed_attempts(&self, key: &str) -> Result<FailedAttempts, StoreError> {
+        let mut get_item = GetItemInput::default();
+        get_item.consistent_read = Some(true);
+        get_item.table_name = ATTEMPT_TABLE.to_string();
+        get_item.key = attempt_key(key);
+
+        Ok(match self.client.get_item(get_item).await?.item {
+            Some(item) => attempts_from_item(item),
+            None => FailedAttempts::default(),
+        })
+    }
+
+    // Atomically counts one more failure and returns the updated row
+    pub async fn record_failed_attempt(
+        &self,
+        key: &str,
+        expires_at: u64,
+    ) -> Result<FailedAttempts, StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":one".to_string(), number_attr(1));
+        values.insert(":expires_at".to_string(), number_attr(expires_at));
+
+        let update_item = UpdateItemInput {
+            table_name: ATTEMPT_TABLE.to_string(),
+            key: attempt_key(key),
+            update_expression: Some("ADD failures :one SET expires_at = :expires_at".to_string()),
+            expression_attribute_values: Some(values),
+            return_values: Some("ALL_NEW".to_string()),
+            ..Default::default()
+        };
+        let output = self.client.update_item(update_item).await?;
+        Ok(attempts_from_item(output.attributes.unwrap_or_default()))
+    }
+
+    pub async fn lock_attempts(
+        &self,
+        key: &str,
+        locked_until: u64,
+        expires_at: u64,
+    ) -> Result<(), StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":locked_until".to_string(), number_attr(locked_until));
+        values.insert(":expires_at".to_string(), number_attr(expires_at));
+
+        let update_item = UpdateItemInput {
+            table_name: ATTEMPT_TABLE.to_string(),
+            key: attempt_key(key),
+            update_expression: Some(
+                "SET locked_until = :locked_until, expires_at = :expires_at".to_string(),
+            ),
+            expression_attribute_values: Some(values),
+            ..Default::default()
+        };
+        self.client.update_item(update_item).await?;
+        Ok(())
+    }
+
+    pub async fn clear_failed_attempts(&self, key: &str) -> Result<(), StoreError> {
+        let delete_item = DeleteItemInput {
+            table_name: ATTEMPT_TABLE.to_string(),
+            key: attempt_key(key),
+            ..Default::default()
+        };
+        self.client.delete_item(delete_item).await?;
+        Ok(())
+    }
+}
+
+fn attempt_key(key: &str) -> HashMap<String, AttributeValue> {
+    let mut attempt_key = HashMap::new();
+    attempt_key.insert("id".to_string(), string_attr(key));
+    attempt_key
+}
+
+fn attempts_from_item(mut item: HashMap<String, AttributeValue>) -> FailedAttempts {
+    FailedAttempts {
+        failures: number_value(&mut item, "failures"),
+        locked_until: number_value(&mut item, "locked_until"),
+        expires_at: number_value(&mut item, "expires_at"),
+    }
+}
@@ -0,0 +1,568 @@
+use super::events::Event;
+use super::token::DEFAULT_ROLE;
+use super::user::{Location, User};
+use log::info;
+use outbox::outbox_put;
+use profiles::{gender_attr, gender_value};
+use rusoto_core::RusotoError;
+use rusoto_dynamodb::{
+    AttributeDefinition, AttributeValue, CreateGlobalSecondaryIndexAction, CreateTableInput,
+    DescribeTableError, DescribeTableInput, DynamoDb, DynamoDbClient, GetItemInput,
+    GlobalSecondaryIndex, GlobalSecondaryIndexUpdate, KeySchemaElement, Projection,
+    ProvisionedThroughput, Put, QueryInput, Tag, TimeToLiveSpecification, TransactWriteItem,
+    UpdateItemError, UpdateItemInput, UpdateTableInput, UpdateTimeToLiveInput,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+pub mod attempts;
+pub mod outbox;
+pub mod profiles;
+pub mod resets;
+pub mod revocations;
+pub mod sessions;
+pub mod totp;
+
+pub const USER_TABLE: &str = "date-app-user-service";
+pub const SESSION_TABLE: &str = "date-app-user-sessions";
+pub const REVOCATION_TABLE: &str = "date-app-user-revocations";
+pub const ATTEMPT_TABLE: &str = "date-app-user-login-attempts";
+pub const RESET_TABLE: &str = "date-app-user-password-resets";
+pub const OUTBOX_TABLE: &str = "date-app-user-event-outbox";
+pub const OUTBOX_DEAD_LETTER_TABLE: &str = "date-app-user-event-dead-letters";
+// The one partition of OUTBOX_TABLE, so events can be queried in order
+pub const OUTBOX_PARTITION: &str = "outbox";
+
+// Global secondary index of USER_TABLE on uid, projecting only the keys
+pub const UID_INDEX: &str = "uid-index";
+
+const INDEX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Items carrying this attribute are expired by DynamoDB once the epoch it holds has passed
+const TTL_ATTRIBUTE: &str = "expires_at";
+
+#[derive(Debug)]
+pub enum StoreError {
+    ConditionFailed,
+    Dynamo(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::ConditionFailed => write!(f, "conditional check failed"),
+            StoreError::Dynamo(err) => write!(f, "dynamodb: {}", err),
+        }
+    }
+}
+
+impl Error for StoreError {}
+
+impl<E: Error + 'static> From<RusotoError<E>> for StoreError {
+    fn from(err: RusotoError<E>) -> Self {
+        StoreError::Dynamo(err.to_string())
+    }
+}
+
+pub struct StoredUser {
+    pub uid: String,
+    pub username: String,
+    pub password: String,
+    pub location: Location, // Storing longitude/latitude as its required to find users shard
+    pub roles: Vec<String>,
+    pub first_name: String,
+    pub last_name: String,
+    pub age: i32,
+    pub gender: i32,
+    pub bio: String,
+    pub version: u64, // bumped by every profile update, rows from before versioning read 0
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+}
+
+/*
+Everything the user service persists goes through here so the
+RPC handlers never build DynamoDB requests themselves
+*/
+pub struct UserStore {
+    client: DynamoDbClient,
+}
+
+impl UserStore {
+    pub fn new(client: DynamoDbClient) -> Self {
+        Self { client }
+    }
+
+    pub async fn create_tables(&self) {
+        self.create_table(CreateTableInput {
+            attribute_definitions: vec![
+                AttributeDefinition {
+                    attribute_name: "username".to_string(),
+                    attribute_type: "S".to_string(),
+                },
+                uid_attribute(),
+            ],
+            key_schema: vec![KeySchemaElement {
+                attribute_name: "username".to_string(),
+                key_type: "HASH".to_string(),
+            }],
+            global_secondary_indexes: Some(vec![GlobalSecondaryIndex {
+                index_name: UID_INDEX.to_string(),
+                key_schema: uid_key_schema(),
+                projection: uid_projection(),
+                provisioned_throughput: Some(provisioned_throughput()),
+            }]),
+            ..table_input(USER_TABLE)
+        })
+        .await;
+        self.migrate_uid_index().await;
+
+        self.create_table(CreateTableInput {
+            attribute_definitions: vec![
+                AttributeDefinition {
+                    attribute_name: "uid".to_string(),
+                    attribute_type: "S".to_string(),
+                },
+                AttributeDefinition {
+                    attribute_name: "session_id".to_string(),
+                    attribute_type: "S".to_string(),
+                },
+            ],
+            key_schema: vec![
+                KeySchemaElement {
+                    attribute_name: "uid".to_string(),
+                    key_type: "HASH".to_string(),
+                },
+                KeySchemaElement {
+                    attribute_name: "session_id".to_string(),
+                    key_type: "RANGE".to_string(),
+                },
+            ],
+            ..table_input(SESSION_TABLE)
+        })
+        .await;
+        self.enable_ttl(SESSION_TABLE).await;
+
+        self.create_table(CreateTableInput {
+            attribute_definitions: vec![AttributeDefinition {
+                attribute_name: "id".to_string(),
+                attribute_type: "S".to_string(),
+            }],
+            key_schema: vec![KeySchemaElement {
+                attribute_name: "id".to_string(),
+                key_type: "HASH".to_string(),
+            }],
+            ..table_input(REVOCATION_TABLE)
+        })
+        .await;
+        self.enable_ttl(REVOCATION_TABLE).await;
+
+        self.create_table(CreateTableInput {
+            attribute_definitions: vec![AttributeDefinition {
+                attribute_name: "id".to_string(),
+                attribute_type: "S".to_string(),
+            }],
+            key_schema: vec![KeySchemaElement {
+                attribute_name: "id".to_string(),
+                key_type: "HASH".to_string(),
+            }],
+            ..table_input(ATTEMPT_TABLE)
+        })
+        .await;
+        self.enable_ttl(ATTEMPT_TABLE).await;
+
+        self.create_table(CreateTableInput {
+            attribute_definitions: vec![AttributeDefinition {
+                attribute_name: "token_hash".to_string(),
+                attribute_type: "S".to_string(),
+            }],
+            key_schema: vec![KeySchemaElement {
+                attribute_name: "token_hash".to_string(),
+                key_type: "HASH".to_string(),
+            }],
+            ..table_input(RESET_TABLE)
+        })
+        .await;
+        self.enable_ttl(RESET_TABLE).await;
+
+        for table in &[OUTBOX_TABLE, OUTBOX_DEAD_LETTER_TABLE] {
+            self.create_table(CreateTableInput {
+                attribute_definitions: vec![
+                    AttributeDefinition {
+                        attribute_name: "partition".to_string(),
+                        attribute_type: "S".to_string(),
+                    },
+                    AttributeDefinition {
+                        attribute_name: "sequence".to_string(),
+                        attribute_type: "S".to_string(),
+                    },
+                ],
+                key_schema: vec![
+                    KeySchemaElement {
+                        attribute_name: "partition".to_string(),
+                        key_type: "HASH".to_string(),
+                    },
+                    KeySchemaElement {
+                        attribute_name: "sequence".to_string(),
+                        key_type: "RANGE".to_string(),
+                    },
+                ],
+                ..table_input(table)
+            })
+            .await;
+        }
+    }
+
+    async fn create_table(&self, create_table_input: CreateTableInput) {
+        let table_name = create_table_input.table_name.clone();
+        info!("Checking if table {} exists", table_name);
+        match self
+            .client
+            .describe_table(DescribeTableInput {
+                table_name: table_name.clone(),
+            })
+            .await
+        {
+            Ok(_) => {
+                info!("Table {} exists", table_name);
+            }
+            Err(RusotoError::Service(DescribeTableError::ResourceNotFound(_))) => {
+                info!("Table {} does not exist: Creating", table_name);
+                match self.client.create_table(create_table_input).await {
+                    Ok(_) => (),
+                    Err(err) => panic!("error creating table {}: {}", table_name, err),
+                };
+            }
+            Err(err) => panic!("error creating table {}: {}", table_name, err),
+        }
+    }
+
+    /*
+    Tables created before the uid index existed get it added here.
+    DynamoDB backfills the index from the existing items, lookups by
+    uid only work once that finished so startup waits for it.
+    */
+    async fn migrate_uid_index(&self) {
+        let mut created = false;
+        loop {
+            let status = match self
+                .client
+                .describe_table(DescribeTableInput {
+                    table_name: USER_TABLE.to_string(),
+                })
+                .await
+            {
+                Ok(output) => output
+                    .table
+                    .and_then(|table| table.global_secondary_indexes)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|index| index.index_name.as_deref() == Some(UID_INDEX))
+                    .map(|index| index.index_status.unwrap_or_default()),
+                Err(err) => panic!("error describing table {}: {}", USER_TABLE, err),
+            };
+
+            match status {
+                Some(status) if status == "ACTIVE" => return,
+                Some(status) => info!("Index {} is {}: Waiting", UID_INDEX, status),
+                None if created => info!("Index {} not visible yet: Waiting", UID_INDEX),
+                None => {
+                    info!("Index {} does not exist: Creating", UID_INDEX);
+                    let update_table = UpdateTableInput {
+                        table_name: USER_TABLE.to_string(),
+                        attribute_definitions: Some(vec![uid_attribute()]),
+                        global_secondary_index_updates: Some(vec![GlobalSecondaryIndexUpdate {
+                            create: Some(CreateGlobalSecondaryIndexAction {
+                                index_name: UID_INDEX.to_string(),
+                                key_schema: uid_key_schema(),
+                                projection: uid_projection(),
+                                provisioned_throughput: Some(provisioned_throughput()),
+                            }),
+                            ..Default::default()
+                        }]),
+                        ..Default::default()
+                    };
+                    if let Err(err) = self.client.update_table(update_table).await {
+                        panic!("error creating index {}: {}", UID_INDEX, err);
+                    }
+                    created = true;
+                }
+            }
+            delay_for(INDEX_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn enable_ttl(&self, table_name: &str) {
+        let ttl_input = UpdateTimeToLiveInput {
+            table_name: table_name.to_string(),
+            time_to_live_specification: TimeToLiveSpecification {
+                attribute_name: TTL_ATTRIBUTE.to_string(),
+                enabled: true,
+            },
+        };
+        // Fails when TTL is already enabled, which is the state we want anyway
+        if let Err(err) = self.client.update_time_to_live(ttl_input).await {
+            info!("TTL on {} not updated: {}", table_name, err);
+        }
+    }
+
+    // Writes `event` to the outbox along with the user
+    pub async fn create_user(&self, user: User, event: &Event) -> Result<(), StoreError> {
+        let mut put_item = Put::default();
+        put_item.table_name = USER_TABLE.to_string();
+        put_item.condition_expression = Some(String::from("attribute_not_exists(username)"));
+        put_item
+            .item
+            .insert("username".to_string(), string_attr(user.username));
+        put_item
+            .item
+            .insert("password".to_string(), string_attr(user.password));
+        put_item
+            .item
+            .insert("uid".to_string(), string_attr(user.uid));
+
+        // Profile
+        put_item
+            .item
+            .insert("first_name".to_string(), string_attr(user.first_name));
+        put_item
+            .item
+            .insert("last_name".to_string(), string_attr(user.last_name));
+        put_item
+            .item
+            .insert("age".to_string(), number_attr(user.age));
+        put_item
+            .item
+            .insert("gender".to_string(), gender_attr(user.gender));
+        put_item
+            .item
+            .insert("bio".to_string(), string_attr(user.bio));
+        put_item.item.insert("version".to_string(), number_attr(1));
+
+        // Location
+        put_item.item.insert(
+            "location".to_string(),
+            location_attr(&user.location.unwrap()),
+        );
+
+        self.transact(vec![
+            TransactWriteItem {
+                put: Some(put_item),
+                ..Default::default()
+            },
+            outbox_put(event),
+        ])
+        .await
+    }
+
+    pub async fn get_user(&self, username: String) -> Result<Option<StoredUser>, StoreError> {
+        let mut get_item = GetItemInput::default();
+        get_item.consistent_read = Some(true);
+        get_item.table_name = USER_TABLE.to_string();
+        get_item.key = user_key(&username);
+
+        let item = match self.client.get_item(get_item).await?.item {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        Ok(Some(user_from_item(item)))
+    }
+
+    // UID_INDEX is eventually consistent so a user created moments ago may not
+    // be found yet, the item itself is then read consistently by username
+    pub async fn get_user_by_uid(&self, uid: &str) -> Result<Option<StoredUser>, StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":uid".to_string(), string_attr(uid));
+
+        let query = QueryInput {
+            table_name: USER_TABLE.to_string(),
+            index_name: Some(UID_INDEX.to_string()),
+            key_condition_expression: Some("uid = :uid".to_string()),
+            expression_attribute_values: Some(values),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let output = self.client.query(query).await?;
+        match output.items.unwrap_or_default().into_iter().next() {
+            Some(mut item) => self.get_user(string_value(&mut item, "username")).await,
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_password(&self, username: &str, password: &str) -> Result<(), StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":password".to_string(), string_attr(password));
+
+        let update_item = UpdateItemInput {
+            table_name: USER_TABLE.to_string(),
+            key: user_key(username),
+            condition_expression: Some("attribute_exists(username)".to_string()),
+            update_expression: Some("SET password = :password".to_string()),
+            expression_attribute_values: Some(values),
+            ..Default::default()
+        };
+        match self.client.update_item(update_item).await {
+            Ok(_) => Ok(()),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                Err(StoreError::ConditionFailed)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Only replaces `old_password`, so a rehash can't undo a concurrent password change
+    pub async fn replace_password_hash(
+        &self,
+        username: &str,
+        old_password: &str,
+        password: &str,
+    ) -> Result<(), StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":old_password".to_string(), string_attr(old_password));
+        values.insert(":password".to_string(), string_attr(password));
+
+        let update_item = UpdateItemInput {
+            table_name: USER_TABLE.to_string(),
+            key: user_key(username),
+            condition_expression: Some("password = :old_password".to_string()),
+            update_expression: Some("SET password = :password".to_string()),
+            expression_attribute_values: Some(values),
+            ..Default::default()
+        };
+        match self.client.update_item(update_item).await {
+            Ok(_) => Ok(()),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                Err(StoreError::ConditionFailed)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn user_key(username: &str) -> HashMap<String, AttributeValue> {
+    let mut key = HashMap::new();
+    key.insert("username".to_string(), string_attr(username));
+    key
+}
+
+fn user_from_item(mut user: HashMap<String, AttributeValue>) -> StoredUser {
+    let mut location = user.remove("location").unwrap().m.unwrap();
+    StoredUser {
+        uid: user.remove("uid").unwrap().s.unwrap(),
+        username: user.remove("username").unwrap().s.unwrap(),
+        password: user.remove("password").unwrap().s.unwrap(),
+        location: Location {
+            longitude: location
+                .remove("longitude")
+                .unwrap()
+                .n
+                .unwrap()
+                .parse::<f64>()
+                .unwrap(),
+            latitude: location
+                .remove("latitude")
+                .unwrap()
+                .n
+                .unwrap()
+                .parse::<f64>()
+                .unwrap(),
+        },
+        roles: user
+            .remove("roles")
+            .and_then(|roles| roles.ss)
+            .unwrap_or_else(|| vec![DEFAULT_ROLE.to_string()]),
+        first_name: string_value(&mut user, "first_name"),
+        last_name: string_value(&mut user, "last_name"),
+        age: number_value(&mut user, "age") as i32,
+        gender: gender_value(&mut user),
+        bio: string_value(&mut user, "bio"),
+        version: number_value(&mut user, "version"),
+        totp_secret: user.remove("totp_secret").and_then(|secret| secret.s),
+        totp_enabled: user
+            .remove("totp_enabled")
+            .and_then(|enabled| enabled.bool)
+            .unwrap_or(false),
+    }
+}
+
+fn uid_attribute() -> AttributeDefinition {
+    AttributeDefinition {
+        attribute_name: "uid".to_string(),
+        attribute_type: "S".to_string(),
+    }
+}
+
+fn uid_key_schema() -> Vec<KeySchemaElement> {
+    vec![KeySchemaElement {
+        attribute_name: "uid".to_string(),
+        key_type: "HASH".to_string(),
+    }]
+}
+
+fn uid_projection() -> Projection {
+    Projection {
+        projection_type: Some("KEYS_ONLY".to_string()),
+        non_key_attributes: None,
+    }
+}
+
+fn provisioned_throughput() -> ProvisionedThroughput {
+    ProvisionedThroughput {
+        read_capacity_units: 1,
+        write_capacity_units: 1,
+    }
+}
+
+fn table_input(table_name: &str) -> CreateTableInput {
+    CreateTableInput {
+        attribute_definitions: vec![],
+        billing_mode: None,
+        global_secondary_indexes: None,
+        key_schema: vec![],
+        local_secondary_indexes: None,
+        provisioned_throughput: Some(provisioned_throughput()),
+        sse_specification: None,
+        stream_specification: None,
+        table_name: table_name.to_string(),
+        tags: Some(vec![Tag {
+            key: "service".to_string(),
+            value: "user-service".to_string(),
+        }]),
+    }
+}
+
+pub(crate) fn string_attr<S: Into<String>>(value: S) -> AttributeValue {
+    let mut attr = AttributeValue::default();
+    attr.s = Some(value.into());
+    attr
+}
+
+pub(crate) fn number_attr<N: ToString>(value: N) -> AttributeValue {
+    let mut attr = AttributeValue::default();
+    attr.n = Some(value.to_string());
+    attr
+}
+
+pub(crate) fn location_attr(location: &Location) -> AttributeValue {
+    let mut hash_location = HashMap::new();
+    hash_location.insert("latitude".to_string(), number_attr(location.latitude));
+    hash_location.insert("longitude".to_string(), number_attr(location.longitude));
+
+    let mut attr = AttributeValue::default();
+    attr.m = Some(hash_location);
+    attr
+}
+
+pub(crate) fn string_value(item: &mut HashMap<String, AttributeValue>, key: &str) -> String {
+    item.remove(key).and_then(|attr| attr.s).unwrap_or_default()
+}
+
+pub(crate) fn number_value(item: &mut HashMap<String, AttributeValue>, key: &str) -> u64 {
+    item.remove(key)
+        .and_then(|attr| attr.n)
+        .and_then(|n| n.parse::<u64>().ok())
+        .unwrap_or_default()
+}
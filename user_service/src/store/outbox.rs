@@ -0,0 +1,198 @@
+use super::super::events::Event;
+use super::{
+    string_attr, StoreError, UserStore, OUTBOX_DEAD_LETTER_TABLE, OUTBOX_PARTITION, OUTBOX_TABLE,
+};
+use log::warn;
+use rusoto_core::RusotoError;
+use rusoto_dynamodb::{
+    AttributeValue, Delete, DeleteItemInput, DynamoDb, Put, QueryInput, TransactWriteItem,
+    TransactWriteItemsError, TransactWriteItemsInput,
+};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/*
+Events are put in the outbox in the same transaction as the change they
+describe, so a change is never stored without its event. Every event shares
+one partition sorted by `sequence`, so the relay reads the oldest ones with a
+single query, publishes them in that order and removes them once published.
+
+`sequence` comes from the clock of the instance that wrote the event, before
+its transaction commits. Two changes to the same user made on instances whose
+clocks disagree, or committing in the other order, can be published out of
+order: per user order is not guaranteed and consumers have to cope with an
+event arriving after a later one.
+*/
+#[derive(Debug, Clone)]
+pub struct PendingEvent {
+    pub sequence: String,
+    pub event: Event,
+}
+
+impl UserStore {
+    // Oldest first, at most `limit` events. Items that don't parse are moved
+    // to the dead letter table so they don't hold up the ones behind them.
+    pub async fn pending_events(&self, limit: usize) -> Result<Vec<PendingEvent>, StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":partition".to_string(), string_attr(OUTBOX_PARTITION));
+        let output = self
+            .client
+            .query(QueryInput {
+                table_name: OUTBOX_TABLE.to_string(),
+                key_condition_expression: Some("#partition = :partition".to_string()),
+                expression_attribute_names: Some(partition_name()),
+                expression_attribute_values: Some(values),
+                scan_index_forward: Some(true),
+                consistent_read: Some(true),
+                limit: Some(limit as i64),
+                ..Default::default()
+            })
+            .await?;
+        let mut pending = vec![];
+        for item in output.items.unwrap_or_default() {
+            match event_from_item(&item) {
+                Ok(event) => pending.push(event),
+                Err(err) => {
+                    warn!(
+                        "Moving unreadable outbox event to the dead letters: {}",
+                        err
+                    );
+                    self.dead_letter(item).await?;
+                }
+            }
+        }
+        Ok(pending)
+    }
+
+    pub async fn remove_event(&self, sequence: &str) -> Result<(), StoreError> {
+        self.client
+            .delete_item(DeleteItemInput {
+                table_name: OUTBOX_TABLE.to_string(),
+                key: outbox_key(sequence),
+                ..Default::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    // Kept as it was for someone to look at, out of the relay's way
+    async fn dead_letter(&self, item: HashMap<String, AttributeValue>) -> Result<(), StoreError> {
+        let sequence = item
+            .get("sequence")
+            .and_then(|attr| attr.s.clone())
+            .unwrap_or_default();
+        let put = TransactWriteItem {
+            put: Some(Put {
+                table_name: OUTBOX_DEAD_LETTER_TABLE.to_string(),
+                item,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let delete = TransactWriteItem {
+            delete: Some(Delete {
+                table_name: OUTBOX_TABLE.to_string(),
+                key: outbox_key(&sequence),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        self.transact(vec![put, delete]).await
+    }
+
+    // Fails with ConditionFailed when any item's condition does
+    pub(crate) async fn transact(&self, items: Vec<TransactWriteItem>) -> Result<(), StoreError> {
+        match self
+            .client
+            .transact_write_items(TransactWriteItemsInput {
+                transact_items: items,
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(RusotoError::Service(TransactWriteItemsError::TransactionCanceled(reasons)))
+                if reasons.contains("ConditionalCheckFailed") =>
+            {
+                Err(StoreError::ConditionFailed)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+// The outbox half of a transaction
+pub(crate) fn outbox_put(event: &Event) -> TransactWriteItem {
+    let mut item = outbox_key(&sequence(&event.id));
+    item.insert(
+        "event".to_string(),
+        string_attr(serde_json::to_string(event).expect("events always serialize")),
+    );
+    TransactWriteItem {
+        put: Some(Put {
+            table_name: OUTBOX_TABLE.to_string(),
+            item,
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+fn outbox_key(sequence: &str) -> HashMap<String, AttributeValue> {
+    let mut key = HashMap::new();
+    key.insert("partition".to_string(), string_attr(OUTBOX_PARTITION));
+    key.insert("sequence".to_string(), string_attr(sequence));
+    key
+}
+
+// `partition` is a reserved word in expressions
+fn partition_name() -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    names.insert("#partition".to_string(), "partition".to_string());
+    names
+}
+
+/*
+Nanoseconds padded to sort as strings, seconds would tie for events
+written in quick succession. The event id keeps two events written in
+the same nanosecond from replacing each other.
+*/
+fn sequence(event_id: &str) -> String {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(n) => format!("{:020}-{}", n.as_nanos() as u64, event_id),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    }
+}
+
+fn event_from_item(item: &HashMap<String, AttributeValue>) -> Result<PendingEvent, String> {
+    let sequence = item.get("sequence").and_then(|attr| attr.s.clone());
+    let event = item.get("event").and_then(|attr| attr.s.as_deref());
+    match (sequence, event) {
+        (Some(sequence), Some(event)) => serde_json::from_str(event)
+            .map(|event| PendingEvent { sequence, event })
+            .map_err(|err| err.to_string()),
+        _ => Err("missing sequence or event".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::events::UserEvent;
+    use super::*;
+
+    #[test]
+    fn unreadable_items_are_told_apart() {
+        let event = Event::new(UserEvent::UserDeleted {
+            uid: "uid-1".to_string(),
+        });
+        let put = outbox_put(&event).put.unwrap();
+        let pending = event_from_item(&put.item).unwrap();
+        assert_eq!(pending.event.id, event.id);
+        assert!(pending.sequence.ends_with(&event.id));
+        assert_eq!(pending.sequence.find('-'), Some(20));
+
+        let mut item = put.item;
+        item.insert("event".to_string(), string_attr("{"));
+        assert!(event_from_item(&item).is_err());
+    }
+}
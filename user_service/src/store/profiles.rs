@@ -0,0 +1,173 @@
+use super::super::events::Event;
+use super::super::user::{Gender, Location};
+use super::outbox::outbox_put;
+use super::{
+    location_attr, number_attr, string_attr, user_key, StoreError, StoredUser, UserStore,
+    USER_TABLE,
+};
+use rusoto_dynamodb::{AttributeValue, Delete, TransactWriteItem, Update};
+use std::collections::HashMap;
+
+// Profile fields to overwrite, `None` leaves the stored value alone
+#[derive(Debug, Default)]
+pub struct ProfileUpdate {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub age: Option<i32>,
+    pub gender: Option<i32>,
+    pub bio: Option<String>,
+    pub location: Option<Location>,
+}
+
+impl ProfileUpdate {
+    // What the stored user looks like once the update is written
+    pub fn apply(&self, user: &mut StoredUser) {
+        if let Some(first_name) = &self.first_name {
+            user.first_name = first_name.clone();
+        }
+        if let Some(last_name) = &self.last_name {
+            user.last_name = last_name.clone();
+        }
+        if let Some(age) = self.age {
+            user.age = age;
+        }
+        if let Some(gender) = self.gender {
+            user.gender = gender;
+        }
+        if let Some(bio) = &self.bio {
+            user.bio = bio.clone();
+        }
+        if let Some(location) = &self.location {
+            user.location = location.clone();
+        }
+        user.version += 1;
+    }
+}
+
+impl UserStore {
+    /*
+    Fails with ConditionFailed when the row is gone or no longer at `version`.
+    `events` go to the outbox in the same transaction.
+    */
+    pub async fn update_profile(
+        &self,
+        username: &str,
+        version: u64,
+        update: ProfileUpdate,
+        events: &[Event],
+    ) -> Result<(), StoreError> {
+        let mut values = HashMap::new();
+        let mut assignments = vec!["version = :next_version".to_string()];
+        values.insert(":next_version".to_string(), number_attr(version + 1));
+
+        let mut set = |field: &str, value: AttributeValue| {
+            assignments.push(format!("{} = :{}", field, field));
+            values.insert(format!(":{}", field), value);
+        };
+        if let Some(first_name) = update.first_name {
+            set("first_name", string_attr(first_name));
+        }
+        if let Some(last_name) = update.last_name {
+            set("last_name", string_attr(last_name));
+        }
+        if let Some(age) = update.age {
+            set("age", number_attr(age));
+        }
+        if let Some(gender) = update.gender {
+            set("gender", gender_attr(gender));
+        }
+        if let Some(bio) = update.bio {
+            set("bio", string_attr(bio));
+        }
+        if let Some(location) = update.location {
+            set("location", location_attr(&location));
+        }
+
+        // Rows written before versioning have no version attribute and read as 0
+        let condition = if version == 0 {
+            "attribute_exists(username) AND attribute_not_exists(version)".to_string()
+        } else {
+            values.insert(":version".to_string(), number_attr(version));
+            "version = :version".to_string()
+        };
+
+        let update_item = TransactWriteItem {
+            update: Some(Update {
+                table_name: USER_TABLE.to_string(),
+                key: user_key(username),
+                condition_expression: Some(condition),
+                update_expression: format!("SET {}", assignments.join(", ")),
+                expression_attribute_values: Some(values),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut items = vec![update_item];
+        items.extend(events.iter().map(outbox_put));
+        self.transact(items).await
+    }
+
+    // Fails with ConditionFailed when there is no such user
+    pub async fn delete_user(&self, username: &str, event: &Event) -> Result<(), StoreError> {
+        let delete_item = TransactWriteItem {
+            delete: Some(Delete {
+                table_name: USER_TABLE.to_string(),
+                key: user_key(username),
+                condition_expression: Some("attribute_exists(username)".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        self.transact(vec![delete_item, outbox_put(event)]).await
+    }
+}
+
+// Stored by name so the item stays readable if the enum is renumbered
+pub(crate) fn gender_attr(gender: i32) -> AttributeValue {
+    string_attr(gender_name(gender))
+}
+
+pub(crate) fn gender_value(item: &mut HashMap<String, AttributeValue>) -> i32 {
+    let name = item.remove("gender").and_then(|attr| attr.s);
+    gender_from_name(name.as_deref().unwrap_or_default()) as i32
+}
+
+// Also how events name it, unknown values are Unspecified
+pub(crate) fn gender_name(gender: i32) -> &'static str {
+    match Gender::from_i32(gender) {
+        Some(Gender::Male) => "Male",
+        Some(Gender::Female) => "Female",
+        Some(Gender::NonBinary) => "NonBinary",
+        Some(Gender::Other) => "Other",
+        Some(Gender::Unspecified) | None => "Unspecified",
+    }
+}
+
+fn gender_from_name(name: &str) -> Gender {
+    match name {
+        "Male" => Gender::Male,
+        "Female" => Gender::Female,
+        "NonBinary" => Gender::NonBinary,
+        "Other" => Gender::Other,
+        _ => Gender::Unspecified,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn genders_round_trip_by_name() {
+        for gender in 0..=4 {
+            let mut item = HashMap::new();
+            item.insert("gender".to_string(), gender_attr(gender));
+            assert_eq!(gender_value(&mut item), gender);
+        }
+        assert_eq!(gender_name(42), "Unspecified");
+        assert_eq!(
+            gender_value(&mut HashMap::new()),
+            Gender::Unspecified as i32
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use super::{
+    number_attr, number_value, string_attr, string_value, StoreError, UserStore, RESET_TABLE,
+};
+use rusoto_dynamodb::{AttributeValue, DeleteItemInput, DynamoDb, GetItemInput, PutItemInput};
+use std::collections::HashMap;
+
+// Keyed by a hash of the reset token, the token itself is only ever sent to the user
+pub struct PasswordReset {
+    pub username: String,
+    pub uid: String,
+    pub expires_at: u64,
+    // Digest of the password hash when the reset was requested, so the
+    // reset dies with that password once it's changed by any means
+    pub password_digest: String,
+}
+
+impl UserStore {
+    pub async fn put_password_reset(
+        &self,
+        token_hash: &str,
+        reset: &PasswordReset,
+    ) -> Result<(), StoreError> {
+        let mut put_item = PutItemInput::default();
+        put_item.table_name = RESET_TABLE.to_string();
+        put_item.item = reset_key(token_hash);
+        put_item
+            .item
+            .insert("username".to_string(), string_attr(reset.username.as_str()));
+        put_item
+            .item
+            .insert("uid".to_string(), string_attr(reset.uid.as_str()));
+        put_item
+            .item
+            .insert("expires_at".to_string(), number_attr(reset.expires_at));
+        put_item.item.insert(
+            "password_digest".to_string(),
+            string_attr(reset.password_digest.as_str()),
+        );
+
+        self.client.put_item(put_item).await?;
+        Ok(())
+    }
+
+    pub async fn get_password_reset(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<PasswordReset>, StoreError> {
+        let mut get_item = GetItemInput::default();
+        get_item.consistent_read = Some(true);
+        get_item.table_name = RESET_TABLE.to_string();
+        get_item.key = reset_key(token_hash);
+
+        Ok(self
+            .client
+            .get_item(get_item)
+            .await?
+            .item
+            .map(reset_from_item))
+    }
+
+    // Deletes and returns the reset in one call so a token can only be used once
+    pub async fn take_password_reset(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<PasswordReset>, StoreError> {
+        let delete_item = DeleteItemInput {
+            table_name: RESET_TABLE.to_string(),
+            key: reset_key(token_hash),
+            return_values: Some("ALL_OLD".to_string()),
+            ..Default::default()
+        };
+        let output = self.client.delete_item(delete_item).await?;
+        Ok(output.attributes.map(reset_from_item))
+    }
+}
+
+fn reset_key(token_hash: &str) -> HashMap<String, AttributeValue> {
+    let mut key = HashMap::new();
+    key.insert("token_hash".to_string(), string_attr(token_hash));
+    key
+}
+
+fn reset_from_item(mut item: HashMap<String, AttributeValue>) -> PasswordReset {
+    PasswordReset {
+        username: string_value(&mut item, "username"),
+        uid: string_value(&mut item, "uid"),
+        expires_at: number_value(&mut item, "expires_at"),
+        password_digest: string_value(&mut item, "password_digest"),
+    }
+}
@@ -0,0 +1,91 @@
+use super::{number_attr, string_attr, StoreError, UserStore, REVOCATION_TABLE};
+use futures::try_join;
+use rusoto_dynamodb::{AttributeValue, DynamoDb, GetItemInput, PutItemInput};
+use std::collections::HashMap;
+
+/*
+Revoked tokens and per user watermarks share one table, keyed
+`jti#<jti>` and `uid#<uid>`. Both rows expire once every token
+they could apply to has expired on its own.
+*/
+impl UserStore {
+    pub async fn revoke_token(&self, jti: &str, expires_at: u64) -> Result<(), StoreError> {
+        let mut put_item = PutItemInput::default();
+        put_item.table_name = REVOCATION_TABLE.to_string();
+        put_item.item = revocation_key(format!("jti#{}", jti));
+        put_item
+            .item
+            .insert("expires_at".to_string(), number_attr(expires_at));
+
+        self.client.put_item(put_item).await?;
+        Ok(())
+    }
+
+    // Every token of `uid` issued up to `not_before_ms` is treated as revoked
+    pub async fn set_revocation_watermark(
+        &self,
+        uid: &str,
+        not_before_ms: u64,
+        expires_at: u64,
+    ) -> Result<(), StoreError> {
+        let mut put_item = PutItemInput::default();
+        put_item.table_name = REVOCATION_TABLE.to_string();
+        put_item.item = revocation_key(format!("uid#{}", uid));
+        put_item
+            .item
+            .insert("not_before_ms".to_string(), number_attr(not_before_ms));
+        put_item
+            .item
+            .insert("expires_at".to_string(), number_attr(expires_at));
+
+        self.client.put_item(put_item).await?;
+        Ok(())
+    }
+
+    pub async fn is_token_revoked(
+        &self,
+        jti: &str,
+        uid: &str,
+        issued_at_ms: u64,
+        now: u64,
+    ) -> Result<bool, StoreError> {
+        let (token, watermark) = try_join!(
+            self.get_revocation(format!("jti#{}", jti)),
+            self.get_revocation(format!("uid#{}", uid))
+        )?;
+
+        // TTL deletes lazily, rows past their expiry no longer apply
+        let live = |item: &HashMap<String, AttributeValue>| number_field(item, "expires_at") > now;
+        if token.as_ref().map_or(false, |item| live(item)) {
+            return Ok(true);
+        }
+        Ok(watermark.as_ref().map_or(false, |item| {
+            live(item) && issued_at_ms <= number_field(item, "not_before_ms")
+        }))
+    }
+
+    async fn get_revocation(
+        &self,
+        id: String,
+    ) -> Result<Option<HashMap<String, AttributeValue>>, StoreError> {
+        let mut get_item = GetItemInput::default();
+        get_item.consistent_read = Some(true);
+        get_item.table_name = REVOCATION_TABLE.to_string();
+        get_item.key = revocation_key(id);
+
+        Ok(self.client.get_item(get_item).await?.item)
+    }
+}
+
+fn number_field(item: &HashMap<String, AttributeValue>, key: &str) -> u64 {
+    item.get(key)
+        .and_then(|attr| attr.n.as_ref())
+        .and_then(|n| n.parse::<u64>().ok())
+        .unwrap_or_default()
+}
+
+fn revocation_key(id: String) -> HashMap<String, AttributeValue> {
+    let mut key = HashMap::new();
+    key.insert("id".to_string(), string_attr(id));
+    key
+}
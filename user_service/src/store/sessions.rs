@@ -0,0 +1,199 @@
+use super::{
+    number_attr, number_value, string_attr, string_value, StoreError, UserStore, SESSION_TABLE,
+};
+use rusoto_core::RusotoError;
+use rusoto_dynamodb::{
+    AttributeValue, DeleteItemInput, DynamoDb, GetItemInput, PutItemInput, QueryInput,
+    UpdateItemError, UpdateItemInput,
+};
+use std::collections::HashMap;
+
+/*
+One row per logged in device. Only a hash of the current refresh token
+is kept, `generation` is bumped on every rotation so a token from an
+older generation can be recognised as reused.
+*/
+#[derive(Debug, Clone)]
+pub struct StoredSession {
+    pub uid: String,
+    pub session_id: String,
+    pub device: String,
+    pub generation: u64,
+    pub refresh_hash: String,
+    pub roles: Vec<String>,
+    pub created_at: u64,
+    pub last_used_at: u64,
+    pub expires_at: u64,
+}
+
+impl UserStore {
+    pub async fn put_session(&self, session: &StoredSession) -> Result<(), StoreError> {
+        let mut put_item = PutItemInput::default();
+        put_item.table_name = SESSION_TABLE.to_string();
+        put_item.item = session_key(&session.uid, &session.session_id);
+        put_item
+            .item
+            .insert("device".to_string(), string_attr(session.device.as_str()));
+        put_item
+            .item
+            .insert("generation".to_string(), number_attr(session.generation));
+        put_item.item.insert(
+            "refresh_hash".to_string(),
+            string_attr(session.refresh_hash.as_str()),
+        );
+        if !session.roles.is_empty() {
+            let mut roles_attr = AttributeValue::default();
+            roles_attr.ss = Some(session.roles.clone());
+            put_item.item.insert("roles".to_string(), roles_attr);
+        }
+        put_item
+            .item
+            .insert("created_at".to_string(), number_attr(session.created_at));
+        put_item.item.insert(
+            "last_used_at".to_string(),
+            number_attr(session.last_used_at),
+        );
+        put_item
+            .item
+            .insert("expires_at".to_string(), number_attr(session.expires_at));
+
+        self.client.put_item(put_item).await?;
+        Ok(())
+    }
+
+    pub async fn get_session(
+        &self,
+        uid: &str,
+        session_id: &str,
+    ) -> Result<Option<StoredSession>, StoreError> {
+        let mut get_item = GetItemInput::default();
+        get_item.consistent_read = Some(true);
+        get_item.table_name = SESSION_TABLE.to_string();
+        get_item.key = session_key(uid, session_id);
+
+        Ok(self
+            .client
+            .get_item(get_item)
+            .await?
+            .item
+            .map(session_from_item))
+    }
+
+    // Swaps the refresh token hash only if nobody rotated the session in the meantime
+    pub async fn rotate_session(
+        &self,
+        session: &StoredSession,
+        refresh_hash: &str,
+        now: u64,
+        expires_at: u64,
+    ) -> Result<(), StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":generation".to_string(), number_attr(session.generation));
+        values.insert(
+            ":next_generation".to_string(),
+            number_attr(session.generation + 1),
+        );
+        values.insert(
+            ":old_hash".to_string(),
+            string_attr(session.refresh_hash.as_str()),
+        );
+        values.insert(":new_hash".to_string(), string_attr(refresh_hash));
+        values.insert(":now".to_string(), number_attr(now));
+        values.insert(":expires_at".to_string(), number_attr(expires_at));
+
+        let update_item = UpdateItemInput {
+            table_name: SESSION_TABLE.to_string(),
+            key: session_key(&session.uid, &session.session_id),
+            condition_expression: Some(
+                "generation = :generation AND refresh_hash = :old_hash".to_string(),
+            ),
+            update_expression: Some(
+                "SET generation = :next_generation, refresh_hash = :new_hash, last_used_at = :now, expires_at = :expires_at"
+                    .to_string(),
+            ),
+            expression_attribute_values: Some(values),
+            ..Default::default()
+        };
+
+        match self.client.update_item(update_item).await {
+            Ok(_) => Ok(()),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                Err(StoreError::ConditionFailed)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn list_sessions(&self, uid: &str) -> Result<Vec<StoredSession>, StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":uid".to_string(), string_attr(uid));
+
+        let mut sessions = vec![];
+        let mut exclusive_start_key = None;
+        loop {
+            let query = QueryInput {
+                table_name: SESSION_TABLE.to_string(),
+                key_condition_expression: Some("uid = :uid".to_string()),
+                expression_attribute_values: Some(values.clone()),
+                exclusive_start_key,
+                ..Default::default()
+            };
+            let output = self.client.query(query).await?;
+            sessions.extend(
+                output
+                    .items
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(session_from_item),
+            );
+            match output.last_evaluated_key {
+                Some(key) => exclusive_start_key = Some(key),
+                None => break,
+            }
+        }
+        Ok(sessions)
+    }
+
+    // Returns whether the session existed
+    pub async fn delete_session(&self, uid: &str, session_id: &str) -> Result<bool, StoreError> {
+        let delete_item = DeleteItemInput {
+            table_name: SESSION_TABLE.to_string(),
+            key: session_key(uid, session_id),
+            return_values: Some("ALL_OLD".to_string()),
+            ..Default::default()
+        };
+        let output = self.client.delete_item(delete_item).await?;
+        Ok(output.attributes.is_some())
+    }
+
+    pub async fn delete_sessions(&self, uid: &str) -> Result<(), StoreError> {
+        for session in self.list_sessions(uid).await? {
+            self.delete_session(uid, &session.session_id).await?;
+        }
+        Ok(())
+    }
+}
+
+fn session_key(uid: &str, session_id: &str) -> HashMap<String, AttributeValue> {
+    let mut key = HashMap::new();
+    key.insert("uid".to_string(), string_attr(uid));
+    key.insert("session_id".to_string(), string_attr(session_id));
+    key
+}
+
+fn session_from_item(mut item: HashMap<String, AttributeValue>) -> StoredSession {
+    StoredSession {
+        uid: string_value(&mut item, "uid"),
+        session_id: string_value(&mut item, "session_id"),
+        device: string_value(&mut item, "device"),
+        generation: number_value(&mut item, "generation"),
+        refresh_hash: string_value(&mut item, "refresh_hash"),
+        roles: item
+            .remove("roles")
+            .and_then(|roles| roles.ss)
+            .unwrap_or_default(),
+        created_at: number_value(&mut item, "created_at"),
+        last_used_at: number_value(&mut item, "last_used_at"),
+        expires_at: number_value(&mut item, "expires_at"),
+    }
+}
@@ -0,0 +1,139 @@
+use super::{number_attr, string_attr, user_key, StoreError, UserStore, USER_TABLE};
+use rusoto_core::RusotoError;
+use rusoto_dynamodb::{AttributeValue, DynamoDb, UpdateItemError, UpdateItemInput};
+use std::collections::HashMap;
+
+/*
+TOTP state lives on the user row: `totp_secret`, `totp_enabled`,
+`totp_last_step` (the last accepted time step, to refuse replays)
+and `recovery_codes`, a string set of recovery code hashes
+*/
+impl UserStore {
+    // Fails with ConditionFailed when TOTP is already enabled
+    pub async fn set_totp_secret(&self, username: &str, secret: &str) -> Result<(), StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":secret".to_string(), string_attr(secret));
+        values.insert(":false".to_string(), bool_attr(false));
+
+        self.update_totp(UpdateItemInput {
+            table_name: USER_TABLE.to_string(),
+            key: user_key(username),
+            condition_expression: Some(
+                "attribute_exists(username) AND \
+                 (attribute_not_exists(totp_enabled) OR totp_enabled = :false)"
+                    .to_string(),
+            ),
+            update_expression: Some(
+                "SET totp_secret = :secret, totp_enabled = :false \
+                 REMOVE totp_last_step, recovery_codes"
+                    .to_string(),
+            ),
+            expression_attribute_values: Some(values),
+            ..Default::default()
+        })
+        .await
+    }
+
+    // Fails with ConditionFailed unless `secret` is still the pending enrollment
+    pub async fn enable_totp(
+        &self,
+        username: &str,
+        secret: &str,
+        recovery_code_hashes: Vec<String>,
+    ) -> Result<(), StoreError> {
+        let mut codes = AttributeValue::default();
+        codes.ss = Some(recovery_code_hashes);
+
+        let mut values = HashMap::new();
+        values.insert(":secret".to_string(), string_attr(secret));
+        values.insert(":false".to_string(), bool_attr(false));
+        values.insert(":true".to_string(), bool_attr(true));
+        values.insert(":codes".to_string(), codes);
+
+        self.update_totp(UpdateItemInput {
+            table_name: USER_TABLE.to_string(),
+            key: user_key(username),
+            condition_expression: Some(
+                "totp_secret = :secret AND totp_enabled = :false".to_string(),
+            ),
+            update_expression: Some(
+                "SET totp_enabled = :true, recovery_codes = :codes".to_string(),
+            ),
+            expression_attribute_values: Some(values),
+            ..Default::default()
+        })
+        .await
+    }
+
+    // Records `step` as used, false when it (or a later step) already was
+    pub async fn use_totp_step(&self, username: &str, step: u64) -> Result<bool, StoreError> {
+        let mut values = HashMap::new();
+        values.insert(":step".to_string(), number_attr(step));
+
+        let result = self
+            .update_totp(UpdateItemInput {
+                table_name: USER_TABLE.to_string(),
+                key: user_key(username),
+                condition_expression: Some(
+                    "attribute_exists(totp_secret) AND \
+                     (attribute_not_exists(totp_last_step) OR totp_last_step < :step)"
+                        .to_string(),
+                ),
+                update_expression: Some("SET totp_last_step = :step".to_string()),
+                expression_attribute_values: Some(values),
+                ..Default::default()
+            })
+            .await;
+        used(result)
+    }
+
+    // Removes the code so it can't be used twice, false when it wasn't there
+    pub async fn use_recovery_code(
+        &self,
+        username: &str,
+        code_hash: &str,
+    ) -> Result<bool, StoreError> {
+        let mut code_set = AttributeValue::default();
+        code_set.ss = Some(vec![code_hash.to_string()]);
+
+        let mut values = HashMap::new();
+        values.insert(":code".to_string(), string_attr(code_hash));
+        values.insert(":code_set".to_string(), code_set);
+
+        let result = self
+            .update_totp(UpdateItemInput {
+                table_name: USER_TABLE.to_string(),
+                key: user_key(username),
+                condition_expression: Some("contains(recovery_codes, :code)".to_string()),
+                update_expression: Some("DELETE recovery_codes :code_set".to_string()),
+                expression_attribute_values: Some(values),
+                ..Default::default()
+            })
+            .await;
+        used(result)
+    }
+
+    async fn update_totp(&self, update_item: UpdateItemInput) -> Result<(), StoreError> {
+        match self.client.update_item(update_item).await {
+            Ok(_) => Ok(()),
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                Err(StoreError::ConditionFailed)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn used(result: Result<(), StoreError>) -> Result<bool, StoreError> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(StoreError::ConditionFailed) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn bool_attr(value: bool) -> AttributeValue {
+    let mut attr = AttributeValue::default();
+    attr.bool = Some(value);
+    attr
+}
@@ -0,0 +1,353 @@
+use super::user::{Jwk, TokenClaims, TokenStatus};
+use base64::{encode_config, URL_SAFE_NO_PAD};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use openssl::pkey::Private;
+use openssl::rsa::Rsa;
+use openssl::sha::sha256;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+const DEFAULT_ALGORITHM: Algorithm = Algorithm::RS256;
+const DEFAULT_ISSUER: &str = "user-service";
+const DEFAULT_AUDIENCE: &str = "date-app";
+const DEFAULT_LIFETIME_SEC: u64 = 8 * 60 * 60; // 8 hours in seconds
+const DEFAULT_LEEWAY_SEC: u64 = 30;
+const DEFAULT_REFRESH_LIFETIME_SEC: u64 = 30 * 24 * 60 * 60; // 30 days in seconds
+const CHALLENGE_LIFETIME_SEC: u64 = 5 * 60; // 5 minutes in seconds
+const KEY_BITS: u32 = 2048;
+
+// Appended to the audience of challenge tokens so they are never accepted as access tokens
+const CHALLENGE_AUDIENCE_SUFFIX: &str = "/second-factor";
+
+pub const DEFAULT_ROLE: &str = "user";
+pub const ADMIN_ROLE: &str = "admin";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String, // uid
+    pub aud: String,
+    pub exp: u64,
+    pub iat: u64,
+    pub nbf: u64,
+    pub jti: String,
+    pub iat_ms: u64, // orders the token against revocations made in the same second as iat
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub sid: String, // session the token was issued for
+}
+
+// Proves the password step of a login, exchanged through VerifySecondFactor
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeClaims {
+    pub iss: String,
+    pub sub: String, // uid
+    pub aud: String,
+    pub exp: u64,
+    pub iat: u64,
+    pub nbf: u64,
+    pub jti: String,
+    pub iat_ms: u64,
+    pub username: String,
+    pub device: String,
+}
+
+impl From<Claims> for TokenClaims {
+    fn from(claims: Claims) -> Self {
+        Self {
+            sub: claims.sub,
+            iss: claims.iss,
+            aud: claims.aud,
+            exp: claims.exp,
+            iat: claims.iat,
+            nbf: claims.nbf,
+            jti: claims.jti,
+            roles: claims.roles,
+            sid: claims.sid,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenConfig {
+    pub issuer: String,
+    pub audience: String,
+    pub lifetime: Duration,
+    pub leeway: Duration,
+    pub refresh_lifetime: Duration,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            issuer: DEFAULT_ISSUER.to_string(),
+            audience: DEFAULT_AUDIENCE.to_string(),
+            lifetime: Duration::from_secs(DEFAULT_LIFETIME_SEC),
+            leeway: Duration::from_secs(DEFAULT_LEEWAY_SEC),
+            refresh_lifetime: Duration::from_secs(DEFAULT_REFRESH_LIFETIME_SEC),
+        }
+    }
+}
+
+impl TokenConfig {
+    // JWT_ISSUER, JWT_AUDIENCE, JWT_LIFETIME_SEC, JWT_LEEWAY_SEC and
+    // JWT_REFRESH_LIFETIME_SEC override the defaults
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            issuer: env::var("JWT_ISSUER").unwrap_or(default.issuer),
+            audience: env::var("JWT_AUDIENCE").unwrap_or(default.audience),
+            lifetime: seconds_from_env("JWT_LIFETIME_SEC").unwrap_or(default.lifetime),
+            leeway: seconds_from_env("JWT_LEEWAY_SEC").unwrap_or(default.leeway),
+            refresh_lifetime: seconds_from_env("JWT_REFRESH_LIFETIME_SEC")
+                .unwrap_or(default.refresh_lifetime),
+        }
+    }
+}
+
+// Every numeric setting of the service is read through here, unset is None
+pub(crate) fn number_from_env<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().map(|value| match value.parse::<T>() {
+        Ok(number) => number,
+        Err(_) => panic!("{} must be a number: {}", key, value),
+    })
+}
+
+pub(crate) fn seconds_from_env(key: &str) -> Option<Duration> {
+    number_from_env(key).map(Duration::from_secs)
+}
+
+/*
+Signs tokens with an RSA key so other services can verify them
+with the public half published through GetJwks
+*/
+pub struct TokenIssuer {
+    config: TokenConfig,
+    kid: String,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey<'static>,
+    jwk: Jwk,
+}
+
+impl TokenIssuer {
+    pub fn from_rsa_pem(pem: &[u8], config: TokenConfig) -> Result<Self, Box<dyn Error>> {
+        Self::from_rsa(Rsa::private_key_from_pem(pem)?, config)
+    }
+
+    // Only suitable for local development, every restart invalidates issued tokens
+    pub fn generate(config: TokenConfig) -> Result<Self, Box<dyn Error>> {
+        Self::from_rsa(Rsa::generate(KEY_BITS)?, config)
+    }
+
+    fn from_rsa(rsa: Rsa<Private>, config: TokenConfig) -> Result<Self, Box<dyn Error>> {
+        let kid = encode_config(&sha256(&rsa.public_key_to_der()?)[..16], URL_SAFE_NO_PAD);
+        let n = encode_config(&rsa.n().to_vec(), URL_SAFE_NO_PAD);
+        let e = encode_config(&rsa.e().to_vec(), URL_SAFE_NO_PAD);
+
+        let encoding_key = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem()?)?;
+        let decoding_key = DecodingKey::from_rsa_components(&n, &e).into_static();
+
+        let jwk = Jwk {
+            kty: "RSA".to_string(),
+            kid: kid.clone(),
+            alg: "RS256".to_string(),
+            key_use: "sig".to_string(),
+            n,
+            e,
+        };
+
+        Ok(Self {
+            config,
+            kid,
+            encoding_key,
+            decoding_key,
+            jwk,
+        })
+    }
+
+    pub fn config(&self) -> &TokenConfig {
+        &self.config
+    }
+
+    pub fn issue(&self, uid: String, roles: Vec<String>, sid: String) -> String {
+        let iat_ms = now_millis();
+        let iat = iat_ms / 1000;
+
+        let claims = Claims {
+            iss: self.config.issuer.clone(),
+            sub: uid,
+            aud: self.config.audience.clone(),
+            exp: iat + self.config.lifetime.as_secs(),
+            iat,
+            nbf: iat,
+            jti: Uuid::new_v4().to_string(),
+            iat_ms,
+            roles,
+            sid,
+        };
+        self.sign(&claims)
+    }
+
+    pub fn issue_challenge(&self, uid: String, username: String, device: String) -> String {
+        let iat_ms = now_millis();
+        let iat = iat_ms / 1000;
+        let claims = ChallengeClaims {
+            iss: self.config.issuer.clone(),
+            sub: uid,
+            aud: self.challenge_audience(),
+            exp: iat + CHALLENGE_LIFETIME_SEC,
+            iat,
+            nbf: iat,
+            jti: Uuid::new_v4().to_string(),
+            iat_ms,
+            username,
+            device,
+        };
+        self.sign(&claims)
+    }
+
+    pub fn validate(&self, token: &str) -> Result<Claims, TokenStatus> {
+        self.verify_claims(token, &self.config.audience)
+    }
+
+    pub fn validate_challenge(&self, token: &str) -> Result<ChallengeClaims, TokenStatus> {
+        self.verify_claims(token, &self.challenge_audience())
+    }
+
+    fn challenge_audience(&self) -> String {
+        format!("{}{}", self.config.audience, CHALLENGE_AUDIENCE_SUFFIX)
+    }
+
+    fn sign<T: Serialize>(&self, claims: &T) -> String {
+        let mut header = Header::new(DEFAULT_ALGORITHM);
+        header.kid = Some(self.kid.clone());
+        encode(&header, claims, &self.encoding_key).unwrap()
+    }
+
+    fn verify_claims<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        audience: &str,
+    ) -> Result<T, TokenStatus> {
+        let header = decode_header(token).map_err(|_| TokenStatus::Malformed)?;
+        if header.kid.as_deref() != Some(self.kid.as_str()) {
+            return Err(TokenStatus::BadSignature);
+        }
+
+        let mut validation = Validation::new(DEFAULT_ALGORITHM);
+        validation.leeway = self.config.leeway.as_secs();
+        validation.validate_nbf = true;
+        validation.iss = Some(self.config.issuer.clone());
+        validation.set_audience(&[audience]);
+
+        match decode::<T>(token, &self.decoding_key, &validation) {
+            Ok(data) => Ok(data.claims),
+            Err(err) => Err(match err.kind() {
+                ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm => {
+                    TokenStatus::BadSignature
+                }
+                ErrorKind::ExpiredSignature => TokenStatus::Expired,
+                ErrorKind::ImmatureSignature => TokenStatus::NotYetValid,
+                ErrorKind::InvalidIssuer => TokenStatus::WrongIssuer,
+                ErrorKind::InvalidAudience => TokenStatus::WrongAudience,
+                _ => TokenStatus::Malformed,
+            }),
+        }
+    }
+
+    pub fn jwks(&self) -> Vec<Jwk> {
+        vec![self.jwk.clone()]
+    }
+}
+
+/*
+Opaque to clients: `<uid>.<session id>.<generation>.<secret>`.
+The uid and session id locate the session row, the generation
+tells a stale token apart from a forged one.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefreshToken {
+    pub uid: String,
+    pub session_id: String,
+    pub generation: u64,
+    secret: String,
+}
+
+impl RefreshToken {
+    pub fn generate(uid: String, session_id: String, generation: u64) -> Self {
+        Self {
+            uid,
+            session_id,
+            generation,
+            secret: random_secret(),
+        }
+    }
+
+    // Only the hash is persisted so a leaked session table can't be replayed
+    pub fn hash(&self) -> String {
+        hash_secret(&self.secret)
+    }
+}
+
+impl fmt::Display for RefreshToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.uid, self.session_id, self.generation, self.secret
+        )
+    }
+}
+
+impl FromStr for RefreshToken {
+    type Err = TokenStatus;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 4 || parts.iter().any(|part| part.is_empty()) {
+            return Err(TokenStatus::Malformed);
+        }
+        Ok(Self {
+            uid: parts[0].to_string(),
+            session_id: parts[1].to_string(),
+            generation: parts[2].parse().map_err(|_| TokenStatus::Malformed)?,
+            secret: parts[3].to_string(),
+        })
+    }
+}
+
+// 256 random bits, url safe
+pub fn random_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    encode_config(&secret, URL_SAFE_NO_PAD)
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    encode_config(&sha256(secret.as_bytes()), URL_SAFE_NO_PAD)
+}
+
+pub fn now() -> u64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(n) => n.as_secs(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    }
+}
+
+pub fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    }
+}
@@ -0,0 +1,179 @@
+use base32::Alphabet;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::RngCore;
+
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+const SECRET_LEN: usize = 20; // 160 bits, the HMAC-SHA1 block RFC 4226 recommends
+const DIGITS: u32 = 6;
+const STEP_SEC: u64 = 30;
+// Codes from one step either side are accepted to allow for clock drift
+const SKEW_STEPS: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+
+/*
+RFC 6238 time based one time passwords with the parameters every
+authenticator app supports: HMAC-SHA1, 6 digits, 30 second steps
+*/
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+    base32::encode(ALPHABET, &secret)
+}
+
+// Key URI format understood by authenticator apps
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+        secret = secret,
+        digits = DIGITS,
+        period = STEP_SEC,
+    )
+}
+
+// The time step `code` is valid for, if any. Callers should refuse
+// a step that was already used so a code can't be replayed.
+pub fn verify(secret: &str, code: &str, now: u64) -> Option<u64> {
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let key = base32::decode(ALPHABET, secret)?;
+    let code = code.parse::<u32>().ok()?;
+    let current = now / STEP_SEC;
+    (current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS)
+        .find(|&step| code_at(&key, step).map_or(false, |expected| expected == code))
+}
+
+// The code an authenticator would show at `now`
+pub fn code(secret: &str, now: u64) -> Option<String> {
+    let key = base32::decode(ALPHABET, secret)?;
+    let code = code_at(&key, now / STEP_SEC).ok()?;
+    Some(format!("{:0width$}", code, width = DIGITS as usize))
+}
+
+fn code_at(key: &[u8], step: u64) -> Result<u32, ErrorStack> {
+    let pkey = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &pkey)?;
+    signer.update(&step.to_be_bytes())?;
+    let hmac = signer.sign_to_vec()?;
+
+    // RFC 4226 dynamic truncation
+    let offset = (hmac[hmac.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hmac[offset] & 0x7f,
+        hmac[offset + 1],
+        hmac[offset + 2],
+        hmac[offset + 3],
+    ]);
+    Ok(binary % 10u32.pow(DIGITS))
+}
+
+// `xxxxx-xxxxx`, lowercase base32
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; RECOVERY_CODE_LEN * 5 / 8];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = base32::encode(ALPHABET, &bytes).to_lowercase();
+            format!(
+                "{}-{}",
+                &code[..RECOVERY_CODE_LEN / 2],
+                &code[RECOVERY_CODE_LEN / 2..]
+            )
+        })
+        .collect()
+}
+
+// Recovery codes are compared after this so users can type them loosely
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // RFC 6238 appendix B, truncated to 6 digits
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        assert_eq!(code_at(RFC_KEY, 59 / STEP_SEC).unwrap(), 287082);
+        assert_eq!(code_at(RFC_KEY, 1111111109 / STEP_SEC).unwrap(), 81804);
+        assert_eq!(code_at(RFC_KEY, 1234567890 / STEP_SEC).unwrap(), 5924);
+        assert_eq!(code_at(RFC_KEY, 2000000000 / STEP_SEC).unwrap(), 279037);
+    }
+
+    #[test]
+    fn verify_accepts_adjacent_steps() {
+        let secret = base32::encode(ALPHABET, RFC_KEY);
+        assert_eq!(
+            verify(&secret, "005924", 1234567890),
+            Some(1234567890 / STEP_SEC)
+        );
+        assert_eq!(
+            verify(&secret, "005924", 1234567890 + STEP_SEC),
+            Some(1234567890 / STEP_SEC)
+        );
+        assert_eq!(verify(&secret, "005924", 1234567890 + 3 * STEP_SEC), None);
+    }
+
+    #[test]
+    fn code_is_zero_padded() {
+        let secret = base32::encode(ALPHABET, RFC_KEY);
+        assert_eq!(code(&secret, 1234567890).unwrap(), "005924");
+    }
+
+    #[test]
+    fn verify_rejects_malformed_codes() {
+        let secret = base32::encode(ALPHABET, RFC_KEY);
+        assert_eq!(verify(&secret, "5924", 1234567890), None);
+        assert_eq!(verify(&secret, "+05924", 1234567890), None);
+        assert_eq!(verify("not base32!", "005924", 1234567890), None);
+    }
+
+    #[test]
+    fn recovery_codes_are_unique_and_normalized() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for code in &codes {
+            assert_eq!(code.len(), RECOVERY_CODE_LEN + 1);
+            assert_eq!(
+                normalize_recovery_code(&code.to_uppercase()),
+                code.replace('-', "")
+            );
+        }
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), codes.len());
+    }
+
+    #[test]
+    fn otpauth_uri_encodes_account() {
+        assert_eq!(
+            otpauth_uri("date-app", "a b", "ABC"),
+            "otpauth://totp/date-app:a%20b?secret=ABC&issuer=date-app&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}